@@ -0,0 +1,90 @@
+use std::{io::Result,io::Error,io::ErrorKind,collections::HashMap};
+use serde_json::Value;
+use crate::{lex,phon,langfile};
+use crate::langfile::{LangFile,Problem,RuleEntry,HarmonyEntry,problem};
+
+// Everything needed to gloss in a language, parsed/compiled once when the file is loaded
+pub struct Language {
+    pub lookups : lex::LookupTable,
+    pub attributes : HashMap<String,lex::Attribute>,
+    pub features : Vec<lex::Feature>,
+    pub clitics : HashMap<String,lex::Clitic>,
+    pub compounding : lex::Compounding,
+    pub orthography : phon::SoundChanges,
+    pub phonetic : phon::SoundChanges,
+    pub show_syllables : bool,
+    pub prosody : phon::Prosody,
+    pub harmony : Vec<phon::Harmony>,
+}
+
+fn compile_phon(json : &Value, file : &LangFile, problems : &mut Vec<Problem>) -> Option<(phon::SoundChanges,phon::SoundChanges,phon::Prosody)> {
+    let mgs = match phon::compile_multigraphs(&file.multigraphs) {
+        Ok(mgs) => mgs,
+        Err(errs) => {
+            for (i,e) in errs { problems.push(problem(format!("multigraphs[{}]",langfile::file_index::<String>(json,"multigraphs",i)),e)); }
+            Vec::new()
+        }
+    };
+    let template = |mgs : &Vec<_>| match &file.syllables.template {
+        Some(t) => phon::compile_template(t,&file.cats,mgs).map(Some),
+        None => Ok(None),
+    };
+    let (syllable,phonetic_syllable) = match (template(&mgs),template(&Vec::new())) {
+        (Ok(s),Ok(p)) => (s,p),
+        (Err(e),_) | (_,Err(e)) => {
+            problems.push(problem(String::from("syllables.template"),e));
+            (None,None)
+        }
+    };
+    let orthography = phon::compile_rules(&file.sc,&file.cats,&file.phonemes,&mgs,syllable);
+    // phonetic rules operate on the orthographic output, so multigraphs are not re-applied
+    let phonetic = phon::compile_rules(&file.phonetic,&file.cats,&file.phonemes,&Vec::new(),phonetic_syllable);
+    let prosody = phon::compile_prosody(&file.stress,&file.tone,&file.cats,&mgs);
+    if let Err(e) = &prosody {
+        problems.push(problem(String::from("stress.heavy"),e.clone()));
+    }
+    match (orthography,phonetic,prosody) {
+        (Ok(o),Ok(p),Ok(pr)) => {
+            // `--stage` looks in `sc` first, so a phonetic block of the same name could never be reached
+            for s in p.stages() {
+                if o.stages().contains(&s) {
+                    problems.push(problem(String::from("phonetic"),format!("stage \"{}\" is also in \"sc\"",s)));
+                }
+            }
+            Some((o,p,pr))
+        },
+        (o,p,_) => {
+            for (key,r) in [("sc",o),("phonetic",p)] {
+                for (i,j,e) in r.err().unwrap_or_default() {
+                    let rule = j.map(|j| format!(".rules[{}]",j)).unwrap_or_default();
+                    problems.push(problem(format!("{}[{}]{}",key,langfile::file_index::<RuleEntry>(json,key,i),rule),e));
+                }
+            }
+            None
+        }
+    }
+}
+
+impl Language {
+    pub fn from_json(json : &Value) -> Result<Language> {
+        let (file,mut problems) = langfile::parse(json);
+        problems.extend(langfile::validate(&file));
+        let sound_changes = compile_phon(json,&file,&mut problems);
+        let harmony = phon::compile_harmony(&file.harmony).unwrap_or_else(|errs| {
+            for (i,e) in errs { problems.push(problem(format!("harmony[{}]",langfile::file_index::<HarmonyEntry>(json,"harmony",i)),e)); }
+            Vec::new()
+        });
+        if !problems.is_empty() {
+            let report : Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+            return Err(Error::new(ErrorKind::InvalidData,report.join("\n")));
+        }
+        let (orthography,phonetic,prosody) = sound_changes.unwrap();
+        let lookups = lex::LookupTable::new(&file)?;
+        let attributes = lex::get_attrs(&file,lookups.classes())?;
+        let features = lex::get_features(&file,&attributes,lookups.classes());
+        let clitics = lex::get_clitics(&file)?;
+        let compounding = lex::get_compounding(&file);
+        let show_syllables = file.syllables.show.unwrap_or(false);
+        Ok(Language { lookups, attributes, features, clitics, compounding, orthography, phonetic, show_syllables, prosody, harmony })
+    }
+}
@@ -0,0 +1,474 @@
+use std::{collections::BTreeMap,fmt::Display};
+use serde::{Deserialize,de::DeserializeOwned};
+use serde_json::Value;
+use crate::{lex,phon};
+
+// Typed model of the language file. Entries are parsed one at a time (see `parse`) so that a single
+// malformed entry doesn't hide the problems in the rest of the file.
+
+#[derive(Deserialize,Clone)]
+pub struct ClassEntry {
+    pub label : Option<String>,
+    pub abbr : Option<String>,
+}
+
+// An irregular form is either given in full, or as `{"stem": ...}` for the regular affixes to attach to
+#[derive(Deserialize,Clone)]
+#[serde(untagged)]
+pub enum IrregularEntry {
+    Form(String),
+    Stem { stem : String },
+}
+
+// Where stress falls: "initial", "final", "penultimate" or "antepenultimate", or a syllable number
+// (from 1 at the start of the word, or from -1 at the end)
+#[derive(Deserialize,Clone,Hash,PartialEq,Eq)]
+#[serde(untagged)]
+pub enum StressEntry {
+    Named(String),
+    Syllable(i64),
+}
+
+#[derive(Deserialize,Clone)]
+pub struct VocabEntry {
+    pub gloss : Option<String>,
+    pub class : Option<String>,
+    pub subclass : Option<String>,
+    #[serde(default)]
+    pub synonyms : Vec<String>,
+    #[serde(default)]
+    pub stems : BTreeMap<String,String>,
+    #[serde(default)]
+    pub irregular : BTreeMap<String,IrregularEntry>,
+    pub linker : Option<String>,
+    pub stress : Option<StressEntry>, // lexical stress
+    pub tone : Option<String>,        // tone melody, e.g. "H L"
+}
+
+#[derive(Deserialize,Clone)]
+pub struct AttrEntry {
+    pub form : Option<String>,
+    #[serde(default)]
+    pub forms : BTreeMap<String,String>,
+    pub pos : Option<i64>,
+    pub affects : Option<Vec<String>>,
+    #[serde(rename = "type")]
+    pub kind : Option<String>,
+    pub anchor : Option<String>, // infixes
+    pub copy : Option<String>,   // reduplication
+    pub stem : Option<String>,
+    pub derives : Option<String>, // class of the derived word
+    pub derived_subclass : Option<String>,
+    pub tone : Option<String>,
+}
+
+#[derive(Deserialize,Clone)]
+pub struct CliticEntry {
+    pub form : Option<String>,
+    #[serde(rename = "type")]
+    pub kind : Option<String>, // "proclitic" or "enclitic"
+}
+
+#[derive(Deserialize,Clone,Default)]
+pub struct CompoundingEntry {
+    pub linker : Option<String>,
+    pub gloss : Option<String>,
+    pub head : Option<String>, // "left" or "right"
+    pub sound_changes : Option<bool>,
+}
+
+#[derive(Deserialize,Clone,Default)]
+pub struct SyllableEntry {
+    pub template : Option<String>, // e.g. "(C)(C)V(C)"
+    pub show : Option<bool>,       // show syllable breaks in the phonetic line
+}
+
+#[derive(Deserialize,Clone)]
+pub struct StressConfig {
+    pub position : StressEntry,
+    pub heavy : Option<String>, // regex for the end of a heavy syllable
+}
+
+#[derive(Deserialize,Clone)]
+pub struct ToneConfig {
+    pub marks : BTreeMap<String,String>, // tone -> diacritic, contours like "HL" included
+    pub spread : Option<String>,         // "right" (the default), "left" or "none"
+}
+
+#[derive(Deserialize,Clone)]
+pub struct HarmonyEntry {
+    pub classes : BTreeMap<String,Vec<String>>, // e.g. "back" -> the back vowels
+    pub archiphonemes : BTreeMap<String,BTreeMap<String,String>>, // e.g. "A" -> {"back": "a", "front": "e"}
+    pub default : String,                       // the class used before anything has set one
+    #[serde(default)]
+    pub transparent : Vec<String>,              // segments harmony passes through, even if they're in a class
+    #[serde(default)]
+    pub opaque : BTreeMap<String,String>,       // segments that aren't in a class but set one
+    pub domain : Option<String>,                // "word" (the default) or "morpheme"
+    pub direction : Option<String>,             // "ltr" (the default) or "rtl"
+}
+
+// An entry of `sc` or `phonetic`: a rule, or a block of rules applied the same way
+#[derive(Deserialize,Clone)]
+#[serde(untagged)]
+pub enum RuleEntry {
+    Rule(String),
+    Block(BlockEntry),
+}
+
+#[derive(Deserialize,Clone)]
+pub struct BlockEntry {
+    pub name : Option<String>,      // a stage that `--stage` can stop after
+    pub rules : Vec<String>,
+    pub apply : Option<String>,     // "simultaneous" (the default) or "sequential"
+    pub direction : Option<String>, // "ltr" (the default) or "rtl"
+    pub iterate : Option<bool>,     // reapply each rule until the word stops changing
+}
+
+#[derive(Deserialize,Clone)]
+pub struct FeatureEntry {
+    pub values : Vec<String>,
+    pub default : Option<String>,
+    pub affects : Option<Vec<String>>,
+}
+
+#[derive(Default)]
+pub struct LangFile {
+    pub classes : BTreeMap<String,ClassEntry>,
+    pub vocab : BTreeMap<String,VocabEntry>,
+    pub attributes : BTreeMap<String,AttrEntry>,
+    pub features : BTreeMap<String,FeatureEntry>,
+    pub clitics : BTreeMap<String,CliticEntry>,
+    pub compounding : CompoundingEntry,
+    pub cats : BTreeMap<String,Vec<String>>,
+    pub phonemes : BTreeMap<String,BTreeMap<String,String>>, // phoneme -> feature -> "+" or "-"
+    pub multigraphs : Vec<String>,
+    pub syllables : SyllableEntry,
+    pub stress : Option<StressConfig>,
+    pub tone : Option<ToneConfig>,
+    pub harmony : Vec<HarmonyEntry>,
+    pub sc : Vec<RuleEntry>,
+    pub phonetic : Vec<RuleEntry>,
+}
+
+// A problem found in the language file, along with the JSON path it was found at
+pub struct Problem {
+    pub path : String,
+    pub msg : String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", &self.path, &self.msg)
+    }
+}
+
+pub fn problem(path : String, msg : String) -> Problem {
+    Problem { path, msg }
+}
+
+fn section<T : DeserializeOwned>(json : &Value, key : &str, problems : &mut Vec<Problem>) -> BTreeMap<String,T> {
+    let mut out = BTreeMap::new();
+    match &json[key] {
+        Value::Object(o) => {
+            for (k,v) in o {
+                match serde_json::from_value(v.clone()) {
+                    Ok(entry) => { out.insert(k.clone(),entry); },
+                    Err(e) => problems.push(problem(format!("{}.{}",key,k),e.to_string())),
+                }
+            }
+        },
+        Value::Null => (),
+        _ => problems.push(problem(String::from(key),String::from("expected an object"))),
+    }
+    out
+}
+
+fn object<T : DeserializeOwned+Default>(json : &Value, key : &str, problems : &mut Vec<Problem>) -> T {
+    match &json[key] {
+        Value::Null => T::default(),
+        v => match serde_json::from_value(v.clone()) {
+            Ok(x) => x,
+            Err(e) => {
+                problems.push(problem(String::from(key),e.to_string()));
+                T::default()
+            }
+        }
+    }
+}
+
+fn list<T : DeserializeOwned>(json : &Value, key : &str, problems : &mut Vec<Problem>) -> Vec<T> {
+    let mut out = Vec::new();
+    match &json[key] {
+        Value::Array(xs) => {
+            for (i,v) in xs.iter().enumerate() {
+                match serde_json::from_value(v.clone()) {
+                    Ok(x) => out.push(x),
+                    Err(e) => problems.push(problem(format!("{}[{}]",key,i),e.to_string())),
+                }
+            }
+        },
+        Value::Null => (),
+        _ => problems.push(problem(String::from(key),String::from("expected an array"))),
+    }
+    out
+}
+
+// `list` skips entries that fail to parse, so this maps an index into the parsed list back to the file
+pub fn file_index<T : DeserializeOwned>(json : &Value, key : &str, i : usize) -> usize {
+    match &json[key] {
+        Value::Array(xs) => xs.iter().enumerate()
+            .filter(|(_,v)| serde_json::from_value::<T>((*v).clone()).is_ok())
+            .nth(i).map(|(j,_)| j).unwrap_or(i),
+        _ => i,
+    }
+}
+
+pub fn parse(json : &Value) -> (LangFile,Vec<Problem>) {
+    let mut problems = Vec::new();
+    if !json.is_object() {
+        problems.push(problem(String::from("$"),String::from("expected an object")));
+        return (LangFile::default(),problems);
+    }
+    let file = LangFile {
+        classes : section(json,"classes",&mut problems),
+        vocab : section(json,"vocab",&mut problems),
+        attributes : section(json,"attributes",&mut problems),
+        features : section(json,"features",&mut problems),
+        clitics : section(json,"clitics",&mut problems),
+        compounding : object(json,"compounding",&mut problems),
+        cats : section(json,"cats",&mut problems),
+        phonemes : section(json,"phonemes",&mut problems),
+        multigraphs : list(json,"multigraphs",&mut problems),
+        syllables : object(json,"syllables",&mut problems),
+        stress : object(json,"stress",&mut problems),
+        tone : object(json,"tone",&mut problems),
+        harmony : list(json,"harmony",&mut problems),
+        sc : list(json,"sc",&mut problems),
+        phonetic : list(json,"phonetic",&mut problems),
+    };
+    (file,problems)
+}
+
+// Checks on the lexical side of the file; the sound changes are checked by compiling them (see `lang`)
+pub fn validate(file : &LangFile) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let classes = lex::ClassTable::new(&file.classes);
+    let mut seen : BTreeMap<&str,&str> = ["N","V","M","P"].iter().map(|c| (*c,*c)).collect();
+    for (name,entry) in &file.classes {
+        for n in std::iter::once(name).chain(entry.abbr.iter()) {
+            if let Some(prev) = seen.insert(n,name) {
+                problems.push(problem(format!("classes.{}",name),format!("\"{}\" is already used by class {}",n,prev)));
+            }
+        }
+    }
+    for (word,entry) in &file.vocab {
+        let path = format!("vocab.{}",word);
+        if entry.gloss.is_none() {
+            problems.push(problem(path.clone(),String::from("missing \"gloss\"")));
+        }
+        match &entry.class {
+            None => problems.push(problem(path.clone(),String::from("missing \"class\""))),
+            Some(c) => if classes.parse(c).is_none() {
+                problems.push(problem(format!("{}.class",path),format!("unknown word class \"{}\"",c)));
+            },
+        }
+        if let Some(Err(e)) = entry.stress.as_ref().map(phon::stress_position) {
+            problems.push(problem(format!("{}.stress",path),e));
+        }
+        for key in entry.irregular.keys() {
+            if key.split('+').any(|a| a.is_empty()) {
+                problems.push(problem(format!("{}.irregular",path),format!("\"{}\" should be attributes joined by \"+\"",key)));
+            }
+        }
+    }
+    for (name,entry) in &file.attributes {
+        let path = format!("attributes.{}",name);
+        let reduplicates = entry.kind.as_deref() == Some("reduplication");
+        if entry.form.is_none() && entry.forms.is_empty() && !reduplicates {
+            problems.push(problem(path.clone(),String::from("missing \"form\"")));
+        }
+        match entry.kind.as_deref() {
+            None | Some("prefix") | Some("suffix") | Some("reduplication") => (),
+            Some("infix") => if entry.anchor.is_none() {
+                problems.push(problem(path.clone(),String::from("missing \"anchor\" for infix")));
+            },
+            Some("circumfix") => for f in entry.form.iter().chain(entry.forms.values()) {
+                if !f.contains("...") && !f.contains('…') {
+                    problems.push(problem(path.clone(),format!("circumfix form \"{}\" should be written \"pre...post\"",f)));
+                }
+            },
+            Some(k) => problems.push(problem(format!("{}.type",path),format!("unknown affix type \"{}\"",k))),
+        }
+        if let Some(c) = &entry.derives {
+            if classes.parse(c).is_none() {
+                problems.push(problem(format!("{}.derives",path),format!("unknown word class \"{}\"",c)));
+            }
+        }
+        for (key,r) in [("anchor",&entry.anchor),("copy",&entry.copy)] {
+            if let Some(Err(e)) = r.as_deref().map(phon::rx) {
+                problems.push(problem(format!("{}.{}",path,key),e));
+            }
+        }
+        if entry.form.is_none() && !entry.forms.is_empty() {
+            // without a default form, every word this can attach to needs a subclass-specific one
+            let affects : Vec<_> = entry.affects.iter().flatten().filter_map(|c| classes.parse(c)).collect();
+            for (word,w) in &file.vocab {
+                let affected = w.class.as_ref().and_then(|c| classes.parse(c)).map(|c| affects.contains(&c)).unwrap_or(false);
+                let covered = w.subclass.as_ref().map(|sc| entry.forms.contains_key(sc)).unwrap_or(false);
+                if affected && !covered {
+                    problems.push(problem(format!("{}.forms",path),
+                        format!("no form for vocab.{} (subclass {}) and no default \"form\"",word,w.subclass.as_deref().unwrap_or("none"))));
+                }
+            }
+        }
+        match (entry.pos,entry.kind.as_deref()) {
+            (None,_) => problems.push(problem(path.clone(),String::from("missing \"pos\""))),
+            // pos 0 is a zero morpheme, which has nothing to infix, wrap around or copy
+            (Some(0),Some(k @ ("infix" | "circumfix" | "reduplication"))) =>
+                problems.push(problem(format!("{}.pos",path),format!("a {} can't be a zero morpheme (pos 0)",k))),
+            _ => (),
+        }
+        match &entry.affects {
+            None => problems.push(problem(path.clone(),String::from("missing \"affects\""))),
+            Some(cs) => for (i,c) in cs.iter().enumerate() {
+                if classes.parse(c).is_none() {
+                    problems.push(problem(format!("{}.affects[{}]",path,i),format!("unknown word class \"{}\"",c)));
+                }
+            },
+        }
+    }
+    for (name,entry) in &file.clitics {
+        let path = format!("clitics.{}",name);
+        if entry.form.is_none() {
+            problems.push(problem(path.clone(),String::from("missing \"form\"")));
+        }
+        match entry.kind.as_deref() {
+            Some("proclitic") | Some("enclitic") => (),
+            None => problems.push(problem(path.clone(),String::from("missing \"type\" (\"proclitic\" or \"enclitic\")"))),
+            Some(k) => problems.push(problem(format!("{}.type",path),format!("unknown clitic type \"{}\"",k))),
+        }
+        if file.attributes.contains_key(name) {
+            problems.push(problem(path.clone(),format!("\"{}\" is also an attribute",name)));
+        }
+    }
+    for (p,fs) in &file.phonemes {
+        for (f,v) in fs {
+            if v != "+" && v != "-" {
+                problems.push(problem(format!("phonemes.{}.{}",p,f),format!("expected \"+\" or \"-\", not \"{}\"",v)));
+            }
+        }
+    }
+    if let Some(stress) = &file.stress {
+        if let Err(e) = phon::stress_position(&stress.position) {
+            problems.push(problem(String::from("stress.position"),e));
+        }
+    }
+    if let Some(tone) = &file.tone {
+        match tone.spread.as_deref() {
+            None | Some("right") | Some("left") | Some("none") => (),
+            Some(s) => problems.push(problem(String::from("tone.spread"),format!("expected \"right\", \"left\" or \"none\", not \"{}\"",s))),
+        }
+    }
+    for id in file.cats.keys() {
+        if !id.strip_prefix('@').map(|n| !n.is_empty() && n.chars().all(phon::is_name_char)).unwrap_or(false) {
+            problems.push(problem(format!("cats.{}",id),String::from("category ids are \"@\" and a name of letters and digits")));
+        }
+    }
+    for (key,set) in [("stress",file.stress.is_some()),("tone",file.tone.is_some())] {
+        if set && file.syllables.template.is_none() {
+            problems.push(problem(String::from(key),String::from("needs a syllable template (\"syllables\")")));
+        }
+    }
+    let tones = file.vocab.iter().map(|(w,e)| (format!("vocab.{}.tone",w),&e.tone))
+        .chain(file.attributes.iter().map(|(a,e)| (format!("attributes.{}.tone",a),&e.tone)));
+    for (path,tone) in tones.filter(|(_,t)| t.is_some()) {
+        let marks = match &file.tone {
+            Some(cfg) => &cfg.marks,
+            None => {
+                problems.push(problem(path,String::from("tones need a \"tone\" section")));
+                continue;
+            }
+        };
+        for t in tone.iter().flat_map(|t| t.split_whitespace()).filter(|t| !marks.contains_key(*t)) {
+            problems.push(problem(path.clone(),format!("tone \"{}\" has no mark in \"tone.marks\"",t)));
+        }
+    }
+    match file.compounding.head.as_deref() {
+        None | Some("left") | Some("right") => (),
+        Some(h) => problems.push(problem(String::from("compounding.head"),format!("expected \"left\" or \"right\", not \"{}\"",h))),
+    }
+    let mut owner : BTreeMap<&String,&String> = BTreeMap::new();
+    for (name,entry) in &file.features {
+        let path = format!("features.{}",name);
+        for v in &entry.values {
+            if let Some(prev) = owner.insert(v,name) {
+                problems.push(problem(format!("{}.values",path),format!("\"{}\" is already a value of {}",v,prev)));
+            }
+        }
+        if let Some(d) = &entry.default {
+            if !entry.values.contains(d) {
+                problems.push(problem(format!("{}.default",path),format!("\"{}\" is not one of the values",d)));
+            }
+            if entry.affects.is_none() && !file.attributes.contains_key(d) {
+                problems.push(problem(path.clone(),format!("default \"{}\" is not a defined attribute, so \"affects\" is needed",d)));
+            }
+        }
+        for (i,c) in entry.affects.iter().flatten().enumerate() {
+            if classes.parse(c).is_none() {
+                problems.push(problem(format!("{}.affects[{}]",path,i),format!("unknown word class \"{}\"",c)));
+            }
+        }
+    }
+    problems
+}
+
+// Things that are legal but probably mistakes
+pub fn lint(json : &Value, file : &LangFile) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut by_gloss : BTreeMap<String,Vec<&String>> = BTreeMap::new();
+    for (word,entry) in &file.vocab {
+        if let Some(g) = &entry.gloss {
+            by_gloss.entry(g.to_lowercase()).or_default().push(word);
+        }
+    }
+    for (gloss,words) in by_gloss {
+        if words.len() > 1 {
+            let listing : Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+            problems.push(problem(format!("vocab.{}",words[0]),
+                format!("gloss \"{}\" is shared with {}, so {{{}}} is ambiguous",gloss,listing[1..].join(", "),gloss)));
+        }
+    }
+    let mut by_features : BTreeMap<&BTreeMap<String,String>,&String> = BTreeMap::new();
+    for (p,fs) in &file.phonemes {
+        if let Some(other) = by_features.insert(fs,p) {
+            problems.push(problem(format!("phonemes.{}",p),
+                format!("has the same features as {}, so feature changes can't tell them apart",other)));
+        }
+    }
+    for (name,entry) in &file.attributes {
+        if let Some(stem) = &entry.stem {
+            if !file.vocab.values().any(|w| w.stems.contains_key(stem)) {
+                problems.push(problem(format!("attributes.{}.stem",name),
+                    format!("no word has a \"{}\" stem, so the lemma is always used",stem)));
+            }
+        }
+    }
+    for (key,entries) in [("sc",&file.sc),("phonetic",&file.phonetic)] {
+        for (i,entry) in entries.iter().enumerate() {
+            // entries that failed to parse aren't in the list, so count in the file itself
+            let i = file_index::<RuleEntry>(json,key,i);
+            let rules = match entry {
+                RuleEntry::Rule(r) => vec![(format!("{}[{}]",key,i),r)],
+                RuleEntry::Block(b) => b.rules.iter().enumerate().map(|(j,r)| (format!("{}[{}].rules[{}]",key,i,j),r)).collect(),
+            };
+            for (path,rule) in rules {
+                for col in phon::plus_after_atom(rule) {
+                    problems.push(problem(path.clone(),
+                        format!("column {}: \"+\" is a morpheme boundary, not the regex quantifier (write \"{{1,}}\" for that)",col)));
+                }
+            }
+        }
+    }
+    problems
+}
@@ -1,8 +1,7 @@
-use std::{cmp::Ordering,io::Result,io::Error,collections::HashSet,collections::HashMap,fmt::Display};
-use serde_json::Value;
-//use regex::Regex;
-
-// static mut lookups : Option<HashMap<String,&String>> = None;
+use std::{io::Result,io::Error,collections::HashSet,collections::HashMap,collections::BTreeMap,fmt::Display};
+use regex::Regex;
+use crate::phon;
+use crate::langfile::{LangFile,VocabEntry,AttrEntry,ClassEntry,FeatureEntry,IrregularEntry,CliticEntry,StressEntry};
 
 fn cuo(s : &str) -> Error {
     Error::new(std::io::ErrorKind::Other,s)
@@ -17,16 +16,103 @@ pub enum Wordclass {
     Custom(u32), // this should probably be more than fine for now
 }
 
+// Word classes declared in the `classes` section, on top of the default N/V/M/P.
+// Classes can be referred to by name or by abbreviation.
+pub struct ClassTable {
+    names : HashMap<String,Wordclass>,
+    labels : HashMap<Wordclass,String>,
+}
+
+impl ClassTable {
+    pub fn new(classes : &BTreeMap<String,ClassEntry>) -> ClassTable {
+        let mut names = HashMap::new();
+        let mut labels = HashMap::new();
+        for (s,d) in [("N",DefaultWordclass::N),("V",DefaultWordclass::V),("M",DefaultWordclass::M),("P",DefaultWordclass::P)] {
+            names.insert(String::from(s),Wordclass::Default(d.clone()));
+            labels.insert(Wordclass::Default(d),String::from(s));
+        }
+        for (i,(name,entry)) in classes.iter().enumerate() {
+            let c = Wordclass::Custom(i as u32);
+            names.insert(name.clone(),c.clone());
+            if let Some(abbr) = &entry.abbr { names.insert(abbr.clone(),c.clone()); }
+            labels.insert(c,entry.label.clone().unwrap_or(name.clone()));
+        }
+        ClassTable {names, labels}
+    }
+
+    pub fn parse(&self, s : &str) -> Option<Wordclass> {
+        self.names.get(s).cloned()
+    }
+
+    pub fn label(&self, c : &Wordclass) -> &str {
+        self.labels.get(c).map(|l| l.as_str()).unwrap_or("?")
+    }
+
+    pub fn all(&self) -> impl Iterator<Item=&Wordclass> {
+        self.labels.keys()
+    }
+}
+
+// How an attribute's form combines with the stem. Prefixes and suffixes are the default, depending on the slot.
+#[derive(Clone)]
+pub enum AffixType {
+    Prefix,
+    Suffix,
+    Infix(Regex),                 // inserted after the first match of the anchor
+    Circumfix,                    // form is written "ge...t"
+    Reduplication(Option<Regex>), // copies the first match of the pattern, or the whole stem
+}
+
+impl PartialEq for AffixType {
+    fn eq(&self, other : &AffixType) -> bool {
+        match (self,other) {
+            (AffixType::Prefix,AffixType::Prefix) => true,
+            (AffixType::Suffix,AffixType::Suffix) => true,
+            (AffixType::Circumfix,AffixType::Circumfix) => true,
+            (AffixType::Infix(a),AffixType::Infix(b)) => a.as_str() == b.as_str(),
+            (AffixType::Reduplication(a),AffixType::Reduplication(b)) =>
+                a.as_ref().map(|r| r.as_str()) == b.as_ref().map(|r| r.as_str()),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for AffixType {}
+
 #[derive(PartialEq,Eq,Clone)]
 pub struct Attribute {
     name: String,
     form: String,
-    place: Ordering,
+    forms: BTreeMap<String,String>, // subclass-specific forms, `form` is the fallback
+    slot: i64, // <0 for prefixes, >0 for suffixes, counting outwards from the root; 0 has no form
+    kind: AffixType,
+    stem: Option<String>, // which of the word's stems this attaches to, if not the lemma
+    derives: Option<(Wordclass,Option<String>)>, // class (and subclass) of the derived word
+    affects: HashSet<Wordclass>,
+    tone: Vec<String>,
+}
+
+// Clitics attach to whole words of any class, outside all affixes, with `=`
+#[derive(Hash,PartialEq,Eq,Clone)]
+pub struct Clitic {
+    name: String,
+    form: String,
+    proclitic: bool,
+}
+
+// A set of mutually exclusive attributes (e.g. Number: SG/DU/PL). If a word the feature applies to
+// isn't given any of its values, the default (if any) is used.
+pub struct Feature {
+    name: String,
+    values: Vec<String>,
+    default: Option<String>,
     affects: HashSet<Wordclass>,
 }
 
 pub trait Affect {
     fn can_affect(&self, c : Wordclass) -> bool;
+    // Derivational attributes change the class of the word they attach to
+    fn derives(&self) -> Option<Wordclass> { None }
 }
 
 impl Display for Attribute {
@@ -35,10 +121,23 @@ impl Display for Attribute {
     }
 }
 
+impl Attribute {
+    pub fn form_for(&self, subclass : &Option<String>) -> &String {
+        match subclass.as_ref().and_then(|sc| self.forms.get(sc)) {
+            Some(f) => f,
+            None => &self.form,
+        }
+    }
+}
+
 impl Affect for Attribute {
     fn can_affect(&self, c : Wordclass) -> bool {
         self.affects.contains(&c)
     }
+
+    fn derives(&self) -> Option<Wordclass> {
+        self.derives.as_ref().map(|(c,_)| c.clone())
+    }
 }
 
 #[derive(Hash,PartialEq,Eq,Clone)]
@@ -46,149 +145,814 @@ pub struct Word<Attr> {
     lemma : String,
     gloss : String,
     class : Wordclass,
-    subclass : (), // idk might implement later
-    attributes : Vec<Attr>
+    subclass : Option<String>, // declension/conjugation class
+    stems : BTreeMap<String,String>, // named stem alternants, e.g. "perfect"
+    irregular : Vec<(Vec<String>,Irregular)>, // keyed by the attributes that trigger it
+    linker : Option<String>, // overrides the default linker when this is a non-final compound member
+    frozen : Vec<String>, // linkers exempt from sound changes, see `sc_input`
+    stress : Option<StressEntry>, // overrides the language's stress position
+    tone : Vec<String>,           // the root's tone melody
+    attributes : Vec<Attr>,
+    clitics : Vec<Clitic>, // innermost first
+}
+
+// How compounds (`house=boat`) are put together
+pub struct Compounding {
+    linker : String,
+    label : String, // gloss for the linker
+    head_first : bool,
+    sound_changes : bool, // whether sound changes apply to the linker
 }
 
-// Both of these are the same type, but one is for native words, the other is for translation/metalang lookups
-pub struct LookupTable<'a> {
-    lang : Option<HashMap<String,&'a Word<Attribute>>>,
-    txln : Option<HashMap<String,&'a Word<Attribute>>>,
+// Linkers exempt from sound changes are swapped for private use characters while the rules run
+const FROZEN : u32 = 0xF8F0;
+
+// The boundaries `build` puts between morphemes, and what they're shown as. They're kept apart from any
+// `-` or `=` in the forms themselves, so the sound changes only ever see these as boundaries.
+const AFFIX : char = '\u{F8E0}';
+const INFIX_START : char = '\u{F8E1}';
+const INFIX_END : char = '\u{F8E2}';
+const REDUPLICATION : char = '\u{F8E3}';
+const CLITIC : char = '\u{F8E4}';
+const BOUNDARIES : [(char,char);5] = [(AFFIX,'-'),(INFIX_START,'<'),(INFIX_END,'>'),(REDUPLICATION,'~'),(CLITIC,'=')];
+
+// Overrides for particular combinations of attributes: either a full (unsegmentable) form that
+// replaces the lemma and those attributes, or a stem the regular affixes attach to
+#[derive(Hash,PartialEq,Eq,Clone)]
+pub enum Irregular {
+    Form(String),
+    Stem(String),
 }
 
-pub fn add_attr<Attr : Clone+Eq+Affect+Display>(w : Word<Attr>, a : Attr) -> Word<Attr> {
-    let Word {lemma, gloss, class, subclass, mut attributes} = w;
-    if a.can_affect(class.clone()) { attributes.push(a); }
-    Word {lemma, gloss, class, subclass, attributes}
+// Both maps resolve to native words: `lang` is keyed by lemma, `txln` by (lowercased) gloss/synonym
+pub struct LookupTable {
+    lang : HashMap<String,Word<Attribute>>,
+    txln : HashMap<String,Vec<String>>,
+    classes : ClassTable,
 }
 
-pub fn get_word<'a>(s : &'a String, json : &Value) -> Result<Word<Attribute>> {
-    let lemma = String::from(s);
-    let wordinfo = &json["vocab"][s.clone()];
-    match wordinfo {
-        Value::Null => {
-            /*
-            // TODO
-            let lookup = Regex::new(r"^\{(.*)\}$").unwrap();
-            return match lookup.captures(&s.as_str()) {
-                None => Err(cuo(&format!("Word not found: «{}»!",&s.as_str()))),
-                Some(m) => {
-                    let res = _naive_lookup(String::from(&m[1]),&json)?;
-                    _get_word_(res, json)
+impl LookupTable {
+    pub fn len(&self) -> usize {
+        self.lang.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lang.is_empty()
+    }
+
+    pub fn classes(&self) -> &ClassTable {
+        &self.classes
+    }
+
+    pub fn new(file : &LangFile) -> Result<LookupTable> {
+        let classes = ClassTable::new(&file.classes);
+        let mut lang = HashMap::new();
+        let mut txln : HashMap<String,Vec<String>> = HashMap::new();
+        for (word,entry) in &file.vocab {
+            let w = word_from_entry(word,entry,&classes)?;
+            let mut keys = vec![w.gloss.to_lowercase()];
+            keys.extend(entry.synonyms.iter().map(|s| s.to_lowercase()));
+            for k in keys {
+                let lemmas = txln.entry(k).or_default();
+                if !lemmas.contains(word) { lemmas.push(String::from(word)); }
+            }
+            lang.insert(String::from(word),w);
+        }
+        Ok(LookupTable {lang, txln, classes})
+    }
+
+    // Exact gloss/synonym matches win; otherwise fall back to glosses containing `term` as a word
+    // (e.g. `{run}` for "to run") or starting with it
+    fn candidates(&self, term : &str) -> Vec<&String> {
+        let term = term.to_lowercase();
+        let mut out : Vec<&String> = match self.txln.get(&term) {
+            Some(lemmas) => lemmas.iter().collect(),
+            None => {
+                let mut partial = Vec::new();
+                for (key,lemmas) in &self.txln {
+                    let word_match = key.split(|c : char| !c.is_alphanumeric()).any(|k| k == term);
+                    if word_match || key.starts_with(&term) {
+                        partial.extend(lemmas.iter());
+                    }
                 }
+                partial
+            }
+        };
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    pub fn lookup(&self, term : &str) -> Result<&Word<Attribute>> {
+        // an empty term would be a partial match for every gloss
+        if term.trim().is_empty() {
+            return Err(cuo("Nothing to look up in «{}»!"));
+        }
+        let lemmas = self.candidates(term);
+        match lemmas.len() {
+            0 => Err(cuo(&format!("No word glossed as «{}»!",term))),
+            1 => Ok(&self.lang[lemmas[0]]),
+            _ => {
+                let listing : Vec<String> = lemmas.iter()
+                    .map(|l| format!("{} ({}, {})",l,&self.lang[*l].gloss,self.classes.label(&self.lang[*l].class)))
+                    .collect();
+                Err(cuo(&format!("«{{{}}}» is ambiguous, candidates are: {}",term,listing.join(", "))))
             }
-            */
-            ()
-        }
-        _ => ()
-    }
-    let gloss = String::from(Option::unwrap(wordinfo["gloss"].as_str()));
-    let class = match wordinfo["class"].as_str() {
-        Some("N") => Ok(Wordclass::Default(DefaultWordclass::N)),
-        Some("V") => Ok(Wordclass::Default(DefaultWordclass::V)),
-        Some("M") => Ok(Wordclass::Default(DefaultWordclass::M)),
-        Some("P") => Ok(Wordclass::Default(DefaultWordclass::P)),
-        _ => Err(cuo("Unrecognized word class!")) // TODO check for custom class definitions
-    }?;
-    Ok(Word {lemma, gloss, class, subclass : (), attributes : Vec::new()})
-}
-
-// TODO add functions for compouding, derivation, and metalang->conlang lookups
-fn _naive_lookup(term : String, json : &Value) -> Result<&String> {
-    let mut table = HashMap::new();
-    let entry_iter = match &json["vocab"] {
-        Value::Object(o) => o.iter(),
-        _ => return Err(cuo("Unparseable JSON!")),
+        }
+    }
+}
+
+pub fn add_attr<Attr : Clone+Eq+Affect+Display>(w : Word<Attr>, a : Attr, warnings : &mut Vec<String>) -> Word<Attr> {
+    let mut w = w;
+    if a.can_affect(w.class.clone()) {
+        if let Some(c) = a.derives() { w.class = c; }
+        w.attributes.push(a);
+    } else {
+        warnings.push(format!("{} does not apply to «{}», ignoring it",a,w.lemma));
+    }
+    w
+}
+
+pub fn add_clitic<Attr>(w : Word<Attr>, c : Clitic) -> Word<Attr> {
+    let mut w = w;
+    w.clitics.push(c);
+    w
+}
+
+// Tokens are either native lemmas or `{MetalangWord}` lookups
+pub fn get_word(s : &String, table : &LookupTable) -> Result<Word<Attribute>> {
+    if let Some(term) = s.strip_prefix('{').and_then(|t| t.strip_suffix('}')) {
+        return table.lookup(term).cloned();
+    }
+    match table.lang.get(s) {
+        Some(w) => Ok(w.clone()),
+        None => Err(cuo(&format!("Word not found: «{}»!",s))),
+    }
+}
+
+fn word_from_entry(s : &String, wordinfo : &VocabEntry, classes : &ClassTable) -> Result<Word<Attribute>> {
+    let lemma = String::from(s);
+    let gloss = match &wordinfo.gloss {
+        Some(g) => String::from(g),
+        None => return Err(cuo(&format!("Word «{}» has no gloss!",s))),
     };
-    for (word,entry) in entry_iter {
-        let gloss = String::from(Option::unwrap(entry["gloss"].as_str()));
-        assert!(!table.contains_key(&gloss));
-        table.insert(gloss,word);
-    }
-    //lookups = Some(table);
-    if !table.contains_key(&term) { return Err(cuo("nope")); }
-    let translation = table.get(&term).unwrap();
-    Ok(translation)
-    /*
-    match lookups {
-        None => {
-            let table = HashMap::new();
-            let entry_iter = match json["vocab"] {
-                Value::Object(o) => o.iter(),
-                _ => return Err(cuo("Unparseable JSON!")),
+    let class = match wordinfo.class.as_deref().and_then(|c| classes.parse(c)) {
+        Some(c) => c,
+        None => return Err(cuo("Unrecognized word class!")),
+    };
+    let subclass = wordinfo.subclass.clone();
+    let mut irregular = Vec::new();
+    for (key,entry) in &wordinfo.irregular {
+        let attrs = key.split('+').map(String::from).collect();
+        let form = match entry {
+            IrregularEntry::Form(f) => Irregular::Form(f.clone()),
+            IrregularEntry::Stem {stem} => Irregular::Stem(stem.clone()),
+        };
+        irregular.push((attrs,form));
+    }
+    // try the most specific overrides first
+    irregular.sort_by_key(|(attrs,_) : &(Vec<String>,Irregular)| std::cmp::Reverse(attrs.len()));
+    let stems = wordinfo.stems.clone();
+    let linker = wordinfo.linker.clone();
+    let stress = wordinfo.stress.clone();
+    let tone = wordinfo.tone.iter().flat_map(|t| t.split_whitespace()).map(String::from).collect();
+    Ok(Word {lemma, gloss, class, subclass, stems, irregular, linker, frozen : Vec::new(), stress, tone, attributes : Vec::new(), clitics : Vec::new()})
+}
+
+pub fn get_compounding(file : &LangFile) -> Compounding {
+    let c = &file.compounding;
+    Compounding {
+        linker : c.linker.clone().unwrap_or_default(),
+        label : c.gloss.clone().unwrap_or(String::from("LK")),
+        head_first : c.head.as_deref() == Some("left"),
+        sound_changes : c.sound_changes.unwrap_or(true),
+    }
+}
+
+// Roots joined with `=` form a compound, which takes its class, subclass, stems, irregular forms and
+// stress from the head; only the head's forms change, the other members are kept as-is. The members'
+// tones make up the compound's melody.
+pub fn get_compound(s : &String, table : &LookupTable, cfg : &Compounding) -> Result<Word<Attribute>> {
+    let mut parts = Vec::new();
+    for root in s.split('=') {
+        parts.push(get_word(&String::from(root),table)?);
+    }
+    if parts.len() == 1 {
+        return Ok(parts.pop().unwrap());
+    }
+    let head = if cfg.head_first { 0 } else { parts.len()-1 };
+    let mut frozen = Vec::new();
+    // each junction gets one linker, picked by the member on its side away from the head
+    let mut junctions = Vec::new();
+    for k in 0..parts.len()-1 {
+        let modifier = &parts[if k < head { k } else { k + 1 }];
+        let linker = modifier.linker.clone().unwrap_or(cfg.linker.clone());
+        junctions.push(if linker.is_empty() {
+            (AFFIX.to_string(),String::from("-"))
+        } else {
+            let linker = if cfg.sound_changes {
+                linker
+            } else {
+                frozen.push(linker);
+                String::from(char::from_u32(FROZEN + frozen.len() as u32 - 1).unwrap())
             };
-            for (word,entry) in entry_iter {
-                let gloss = String::from(Option::unwrap(entry["gloss"].as_str()));
-                assert!(!table.contains_key(&gloss));
-                table.insert(gloss,word);
+            (format!("{}{}{}",AFFIX,linker,AFFIX),format!("-{}-",cfg.label))
+        });
+    }
+    let mut before = (String::new(),String::new());
+    let mut after = (String::new(),String::new());
+    for (i,p) in parts.iter().enumerate() {
+        if i < head {
+            before = (format!("{}{}{}",before.0,p.lemma,junctions[i].0),format!("{}{}{}",before.1,p.gloss,junctions[i].1));
+        } else if i > head {
+            after = (format!("{}{}{}",after.0,junctions[i-1].0,p.lemma),format!("{}{}{}",after.1,junctions[i-1].1,p.gloss));
+        }
+    }
+    let wrap = |x : &String| format!("{}{}{}",before.0,x,after.0);
+    let h = parts[head].clone();
+    let tone = parts.iter().flat_map(|p| p.tone.iter().cloned()).collect();
+    let stems = h.stems.iter().map(|(k,v)| (k.clone(),wrap(v))).collect();
+    let irregular = h.irregular.iter().map(|(k,v)| (k.clone(),match v {
+        Irregular::Form(f) => Irregular::Form(wrap(f)),
+        Irregular::Stem(f) => Irregular::Stem(wrap(f)),
+    })).collect();
+    Ok(Word {
+        lemma : wrap(&h.lemma),
+        gloss : format!("{}{}{}",before.1,h.gloss,after.1),
+        class : h.class, subclass : h.subclass, stems, irregular, linker : h.linker, frozen,
+        stress : h.stress, tone, attributes : Vec::new(), clitics : Vec::new()
+    })
+}
+
+pub fn get_attrs(file : &LangFile, classes : &ClassTable) -> Result<HashMap<String,Attribute>> {
+    let mut attrs = HashMap::new();
+    for (name,attrinfo) in &file.attributes {
+        attrs.insert(name.clone(),attr_from_entry(name.clone(),attrinfo,classes)?);
+    }
+    Ok(attrs)
+}
+
+pub fn get_features(file : &LangFile, attrs : &HashMap<String,Attribute>, classes : &ClassTable) -> Vec<Feature> {
+    let mut features = Vec::new();
+    for (name,entry) in &file.features {
+        let FeatureEntry {values, default, affects} = entry.clone();
+        // without an explicit list, the feature applies wherever its default value does
+        let affects = match (affects,default.as_ref().and_then(|d| attrs.get(d))) {
+            (Some(cs),_) => cs.iter().filter_map(|c| classes.parse(c)).collect(),
+            (None,Some(a)) => a.affects.clone(),
+            (None,None) => HashSet::new(),
+        };
+        features.push(Feature {name : name.clone(), values, default, affects});
+    }
+    features
+}
+
+// Reject words with two values of one feature, and fill in defaults for features left unspecified
+pub fn apply_features(w : Word<Attribute>, features : &Vec<Feature>, attrs : &HashMap<String,Attribute>, classes : &ClassTable) -> Result<Word<Attribute>> {
+    let mut w = w;
+    for f in features {
+        let given : Vec<&String> = w.attributes.iter().map(|a| &a.name).filter(|n| f.values.contains(n)).collect();
+        if given.len() > 1 {
+            let listing : Vec<&str> = given.iter().map(|n| n.as_str()).collect();
+            return Err(cuo(&format!("«{}» has more than one {}: {}!",w.lemma,f.name,listing.join(", "))));
+        }
+        if given.is_empty() && f.affects.contains(&w.class) {
+            if let Some(d) = &f.default {
+                let a = get_attr(d.clone(),attrs,classes)?;
+                w.attributes.push(a);
             }
-            lookups = Some(table);
-            Ok(table.get(&term).unwrap())
-        },
-        Some(table) => Ok(table.get(&term).unwrap()),
+        }
+    }
+    Ok(w)
+}
+
+pub fn get_clitics(file : &LangFile) -> Result<HashMap<String,Clitic>> {
+    let mut clitics = HashMap::new();
+    for (name,entry) in &file.clitics {
+        let CliticEntry {form, kind} = entry.clone();
+        let proclitic = match kind.as_deref() {
+            Some("proclitic") => true,
+            Some("enclitic") => false,
+            _ => return Err(cuo(&format!("Clitic «{}» needs to be a proclitic or enclitic!",name))),
+        };
+        match form {
+            Some(form) => { clitics.insert(name.clone(),Clitic {name : name.clone(),form,proclitic}); },
+            None => return Err(cuo(&format!("Clitic «{}» needs a form!",name))),
+        }
     }
-    */
+    Ok(clitics)
 }
 
-pub fn get_attr(name : String, json : &Value) -> Result<Attribute> {
-    let attrinfo = &json["attributes"][name.clone()];
-    match attrinfo {
-        Value::Null => {return Ok(null_attr(name));}
-        _ => ()
+// Attributes not defined in the language file gloss as-is and have no form
+pub fn get_attr(name : String, attrs : &HashMap<String,Attribute>, classes : &ClassTable) -> Result<Attribute> {
+    match attrs.get(&name) {
+        Some(a) => Ok(a.clone()),
+        None => Ok(null_attr(name,classes)),
     }
-    let form = String::from(Option::unwrap(attrinfo["form"].as_str()));
-    let place = Option::unwrap(attrinfo["pos"].as_i64()).cmp(&0);
+}
+
+fn attr_from_entry(name : String, attrinfo : &AttrEntry, classes : &ClassTable) -> Result<Attribute> {
+    let reduplicates = attrinfo.kind.as_deref() == Some("reduplication");
+    let (form,pos,cs) = match (&attrinfo.form,attrinfo.pos,&attrinfo.affects) {
+        (Some(f),Some(p),Some(cs)) => (f.clone(),p,cs),
+        (None,Some(p),Some(cs)) if reduplicates || !attrinfo.forms.is_empty() => (String::new(),p,cs),
+        _ => return Err(cuo(&format!("Attribute «{}» needs a form, pos and affects!",&name))),
+    };
+    let forms = attrinfo.forms.clone();
+    let pattern = |r : &Option<String>| -> Result<Option<Regex>> {
+        match r {
+            Some(r) => Regex::new(r).map(Some).map_err(|e| cuo(&e.to_string())),
+            None => Ok(None),
+        }
+    };
+    let kind = match attrinfo.kind.as_deref() {
+        None => if pos < 0 { AffixType::Prefix } else { AffixType::Suffix },
+        Some("prefix") => AffixType::Prefix,
+        Some("suffix") => AffixType::Suffix,
+        Some("circumfix") => AffixType::Circumfix,
+        Some("infix") => match pattern(&attrinfo.anchor)? {
+            Some(r) => AffixType::Infix(r),
+            None => return Err(cuo(&format!("Infix «{}» needs an anchor!",&name))),
+        },
+        Some("reduplication") => AffixType::Reduplication(pattern(&attrinfo.copy)?),
+        Some(k) => return Err(cuo(&format!("Unrecognized affix type «{}»!",k))),
+    };
     let mut affects = HashSet::new();
-    match &attrinfo["affects"] {
-        Value::Array(cs) => {
-            for c in cs {
-                match c.as_str() {
-                    Some("N") => { affects.insert(Wordclass::Default(DefaultWordclass::N)); },
-                    Some("V") => { affects.insert(Wordclass::Default(DefaultWordclass::V)); },
-                    Some("M") => { affects.insert(Wordclass::Default(DefaultWordclass::M)); },
-                    Some("P") => { affects.insert(Wordclass::Default(DefaultWordclass::P)); },
-                    _ => { return Err(cuo("Unrecognized word class!")); }
-                }
-            }
+    for c in cs {
+        match classes.parse(c) {
+            Some(wc) => { affects.insert(wc); },
+            None => { return Err(cuo("Unrecognized word class!")); }
         }
-        _ => {return Err(cuo("Malformed JSON!"));}
     }
-    Ok(Attribute {name,form,place,affects})
+    let stem = attrinfo.stem.clone();
+    let derives = match &attrinfo.derives {
+        Some(c) => match classes.parse(c) {
+            Some(wc) => Some((wc,attrinfo.derived_subclass.clone())),
+            None => return Err(cuo("Unrecognized word class!")),
+        },
+        None => None,
+    };
+    let tone = attrinfo.tone.iter().flat_map(|t| t.split_whitespace()).map(String::from).collect();
+    Ok(Attribute {name,form,forms,slot : pos,kind,stem,derives,affects,tone})
 }
 
-pub fn null_attr(name : String) -> Attribute {
+pub fn null_attr(name : String, classes : &ClassTable) -> Attribute {
     let form = String::from("");
-    let place = 0.cmp(&0);
-    let mut affects = HashSet::new();
-    affects.insert(Wordclass::Default(DefaultWordclass::N));
-    affects.insert(Wordclass::Default(DefaultWordclass::V));
-    affects.insert(Wordclass::Default(DefaultWordclass::M));
-    affects.insert(Wordclass::Default(DefaultWordclass::P));
-    Attribute {name,form,place,affects}
+    let affects = classes.all().cloned().collect();
+    Attribute {name,form,forms : BTreeMap::new(),slot : 0,kind : AffixType::Suffix,stem : None,derives : None,affects,tone : Vec::new()}
 }
 
-pub fn inflect(w : &Word<Attribute>) -> String {
-    let (root,xs) = (&(w.lemma), &(w.attributes));
-    let mut out = String::from(root);
+// Two attributes can't fill the same slot (e.g. PL and DU both in the number slot).
+// Each derivation starts a new word, with slots of its own.
+pub fn check_slots(w : &Word<Attribute>) -> Result<()> {
+    let mut filled : HashMap<i64,&String> = HashMap::new();
+    for attr in &w.attributes {
+        if attr.derives.is_some() {
+            filled.clear();
+            continue;
+        }
+        if attr.slot == 0 { continue; }
+        if let Some(other) = filled.insert(attr.slot,&attr.name) {
+            return Err(cuo(&format!("{} and {} both fill slot {} of «{}»!",other,attr.name,attr.slot,w.lemma)));
+        }
+    }
+    Ok(())
+}
+
+// An infix whose anchor isn't found in what it attaches to has nowhere to go
+pub fn check_infixes(w : &Word<Attribute>) -> Result<()> {
+    match build(w).2.first() {
+        Some(name) => Err(cuo(&format!("{} has nowhere to go in «{}», its anchor doesn't match!",name,w.lemma))),
+        None => Ok(()),
+    }
+}
+
+// Attributes from the innermost outwards: within each derivational layer they're ordered by slot,
+// with the derivational affix itself on the outside
+fn by_slot(xs : &Vec<Attribute>) -> Vec<&Attribute> {
+    let mut out = Vec::new();
+    let mut layer : Vec<&Attribute> = Vec::new();
     for attr in xs {
-        match attr.place {
-            Ordering::Greater => out = out + "-" + &attr.form.clone(),
-            Ordering::Less => out = attr.form.clone() + "-" + &out,
-            Ordering::Equal => ()
+        if attr.derives.is_some() {
+            layer.sort_by_key(|a| a.slot.abs());
+            out.append(&mut layer);
+            out.push(attr);
+        } else {
+            layer.push(attr);
         }
     }
+    layer.sort_by_key(|a| a.slot.abs());
+    out.append(&mut layer);
     out
 }
 
-pub fn gloss(w : &Word<Attribute>) -> String {
-    let (gloss,xs) = (&(w.gloss), &(w.attributes));
-    let mut out = String::from(gloss);
+// Builds the morpheme and gloss lines together, applying attributes from the innermost slot outwards
+// (see `by_slot`) regardless of the order they were given in. Boundaries follow the Leipzig conventions:
+// `-` for affixes, `<>` around infixes, `~` for reduplication and `=` for clitics. Attributes absorbed by an
+// irregular form are glossed with `.`, e.g. `go.PST`, and derived stems are parenthesized in the
+// gloss, e.g. `(happy-NMLZ)-PL`. Also returns the infixes whose anchor didn't match (see `check_infixes`).
+fn build(w : &Word<Attribute>) -> (String,String,Vec<String>) {
+    let names : Vec<&String> = w.attributes.iter().map(|a| &a.name).collect();
+    let irregular = w.irregular.iter().find(|(key,_)| key.iter().all(|k| names.contains(&k)));
+    let mut xs = by_slot(&w.attributes);
+    // the innermost attribute asking for a stem this word has picks the stem
+    let stem = xs.iter().filter_map(|a| a.stem.as_ref()).find_map(|s| w.stems.get(s));
+    let mut morphs = stem.unwrap_or(&w.lemma).clone();
+    let mut glosses = w.gloss.clone();
+    let mut consumed : Vec<&String> = Vec::new();
+    match irregular {
+        Some((key,Irregular::Form(f))) => {
+            morphs = f.clone();
+            glosses = std::iter::once(&w.gloss).chain(key.iter()).cloned().collect::<Vec<_>>().join(".");
+            consumed = key.iter().collect();
+        },
+        Some((_,Irregular::Stem(stem))) => morphs = stem.clone(),
+        None => (),
+    }
+    let mut zero = String::new();
+    for attr in w.attributes.iter().filter(|a| a.slot == 0 && !consumed.contains(&&a.name)) {
+        zero = zero + "[" + &attr.name + "]";
+    }
+    xs.retain(|a| a.slot != 0 && !consumed.contains(&&a.name));
+    let mut subclass = w.subclass.clone();
+    let mut unanchored = Vec::new();
     for attr in xs {
-        match attr.place {
-            Ordering::Greater => out = out + "-" + &attr.name.clone(),
-            Ordering::Less => out = attr.name.clone() + "-" + &out,
-            Ordering::Equal => out = String::from("[") + &attr.name.clone() + "]" + &out,
+        let form = attr.form_for(&subclass);
+        let name = &attr.name;
+        match &attr.kind {
+            // an affix with no form (like an unmarked default) leaves no boundary, and is glossed `dog.SG`
+            AffixType::Prefix | AffixType::Suffix if form.is_empty() => glosses = format!("{}.{}",glosses,name),
+            AffixType::Prefix => {
+                morphs = format!("{}{}{}",form,AFFIX,morphs);
+                glosses = format!("{}-{}",name,glosses);
+            },
+            AffixType::Suffix => {
+                morphs = format!("{}{}{}",morphs,AFFIX,form);
+                glosses = format!("{}-{}",glosses,name);
+            },
+            AffixType::Circumfix => {
+                let (pre,post) = form.split_once("...").or(form.split_once('…')).unwrap_or((form,""));
+                morphs = format!("{}{}{}{}{}",pre,AFFIX,morphs,AFFIX,post);
+                glosses = format!("{}-{}-{}",name,glosses,name);
+            },
+            AffixType::Infix(anchor) => {
+                let at = match anchor.find(&morphs) {
+                    Some(m) => m.end(),
+                    None => {
+                        unanchored.push(name.clone());
+                        0
+                    },
+                };
+                morphs = format!("{}{}{}{}{}",&morphs[..at],INFIX_START,form,INFIX_END,&morphs[at..]);
+                glosses = format!("<{}>{}",name,glosses);
+            },
+            AffixType::Reduplication(pattern) => {
+                let bare : String = morphs.chars().filter(|c| !BOUNDARIES.iter().any(|(b,_)| b == c)).collect();
+                let copy = match pattern {
+                    Some(p) => p.find(&bare).map(|m| String::from(m.as_str())).unwrap_or(bare.clone()),
+                    None => bare.clone(),
+                };
+                if attr.slot < 0 {
+                    morphs = format!("{}{}{}",copy,REDUPLICATION,morphs);
+                    glosses = format!("{}~{}",name,glosses);
+                } else {
+                    morphs = format!("{}{}{}",morphs,REDUPLICATION,copy);
+                    glosses = format!("{}~{}",glosses,name);
+                }
+            },
         }
+        if let Some((_,sc)) = &attr.derives {
+            glosses = format!("({})",glosses);
+            subclass = sc.clone();
+        }
+    }
+    glosses = zero + &glosses;
+    for c in &w.clitics {
+        if c.proclitic {
+            morphs = format!("{}{}{}",c.form,CLITIC,morphs);
+            glosses = format!("{}={}",c.name,glosses);
+        } else {
+            morphs = format!("{}{}{}",morphs,CLITIC,c.form);
+            glosses = format!("{}={}",glosses,c.name);
+        }
+    }
+    (morphs,glosses,unanchored)
+}
+
+// The morpheme line as it should be fed to harmony and the sound changes, every boundary being
+// `phon::BOUNDARY`; `show` it for display
+pub fn sc_input(w : &Word<Attribute>) -> String {
+    build(w).0.chars().map(|c| if BOUNDARIES.iter().any(|(b,_)| *b == c) { phon::BOUNDARY } else { c }).collect()
+}
+
+// A line from `sc_input` (after harmony, which keeps the boundaries where they were) as it's shown
+pub fn show(w : &Word<Attribute>, s : &str) -> String {
+    let mut symbols = build(w).0.chars().filter_map(|c| BOUNDARIES.iter().find(|(b,_)| *b == c).map(|(_,sym)| *sym)).collect::<Vec<_>>().into_iter();
+    let out = s.chars().map(|c| if c == phon::BOUNDARY { symbols.next().unwrap_or('-') } else { c }).collect();
+    thaw(w,out)
+}
+
+// Put back any linkers that were hidden from the sound changes
+pub fn thaw(w : &Word<Attribute>, s : String) -> String {
+    let mut out = s;
+    for (i,l) in w.frozen.iter().enumerate() {
+        out = out.replace(char::from_u32(FROZEN + i as u32).unwrap(),l);
     }
     out
 }
+
+pub fn gloss(w : &Word<Attribute>) -> String {
+    build(w).1
+}
+
+pub fn stress(w : &Word<Attribute>) -> Option<&StressEntry> {
+    w.stress.as_ref()
+}
+
+// The tones of the word's morphemes, in the order the morphemes are pronounced
+pub fn melody(w : &Word<Attribute>) -> Vec<String> {
+    let mut tones = w.tone.clone();
+    for attr in by_slot(&w.attributes) {
+        let t = attr.tone.clone();
+        match &attr.kind {
+            AffixType::Prefix => tones = t.into_iter().chain(tones).collect(),
+            AffixType::Reduplication(_) if attr.slot < 0 => tones = t.into_iter().chain(tones).collect(),
+            // an infix lands after the first syllable or so of what it attaches to
+            AffixType::Infix(_) => {
+                let at = tones.len().min(1);
+                tones.splice(at..at,t);
+            },
+            _ => tones.extend(t),
+        }
+    }
+    tones
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json,Value};
+
+    // The word as `get_gloss_info` puts it together from a `root+ATTR+...` token, and any warnings
+    fn inflect(json : &Value, token : &str) -> Result<(Word<Attribute>,Vec<String>)> {
+        let (file,_) = crate::langfile::parse(json);
+        let table = LookupTable::new(&file)?;
+        let attrs = get_attrs(&file,table.classes())?;
+        let features = get_features(&file,&attrs,table.classes());
+        let clitics = get_clitics(&file)?;
+        let mut warnings = Vec::new();
+        let mut xs = token.split('+');
+        let mut w = get_compound(&String::from(xs.next().unwrap()),&table,&get_compounding(&file))?;
+        for x in xs {
+            w = match clitics.get(x) {
+                Some(c) => add_clitic(w,c.clone()),
+                None => add_attr(w,get_attr(String::from(x),&attrs,table.classes())?,&mut warnings),
+            };
+        }
+        w = apply_features(w,&features,&attrs,table.classes())?;
+        check_slots(&w)?;
+        check_infixes(&w)?;
+        Ok((w,warnings))
+    }
+
+    // The morpheme and gloss lines
+    fn lines(json : &Value, token : &str) -> (String,String) {
+        match inflect(json,token) {
+            Ok((w,_)) => (show(&w,&sc_input(&w)),gloss(&w)),
+            Err(e) => panic!("{}: {}",token,e),
+        }
+    }
+
+
+    fn compound(s : &str, head : &str) -> (String,String) {
+        let json = json!({
+            "vocab": {
+                "kat": {"gloss": "dog", "class": "N"},
+                "bot": {"gloss": "box", "class": "N", "linker": "e"},
+                "ev": {"gloss": "house", "class": "N"}
+            },
+            "compounding": {"linker": "t", "head": head}
+        });
+        let (file,_) = crate::langfile::parse(&json);
+        let table = LookupTable::new(&file).unwrap();
+        let w = get_compound(&String::from(s),&table,&get_compounding(&file)).unwrap();
+        (show(&w,&sc_input(&w)),gloss(&w))
+    }
+
+    #[test]
+    fn right_headed_linkers_come_from_the_member_before() {
+        assert_eq!(compound("kat=bot","right"),(String::from("kat-t-bot"),String::from("dog-LK-box")));
+        assert_eq!(compound("kat=bot=ev","right"),(String::from("kat-t-bot-e-ev"),String::from("dog-LK-box-LK-house")));
+        assert_eq!(compound("bot=kat","right").0,"bot-e-kat");
+    }
+
+    #[test]
+    fn left_headed_linkers_come_from_the_member_after() {
+        assert_eq!(compound("kat=bot","left"),(String::from("kat-e-bot"),String::from("dog-LK-box")));
+        assert_eq!(compound("kat=bot=ev","left"),(String::from("kat-e-bot-t-ev"),String::from("dog-LK-box-LK-house")));
+        assert_eq!(compound("bot=kat","left").0,"bot-t-kat");
+    }
+
+    #[test]
+    fn hyphens_in_lemmas_are_not_boundaries() {
+        let json = json!({
+            "vocab": {"ar-ko": {"gloss": "bird", "class": "N"}},
+            "attributes": {"PL": {"form": "ta", "pos": 1, "affects": ["N"]}}
+        });
+        let (file,_) = crate::langfile::parse(&json);
+        let table = LookupTable::new(&file).unwrap();
+        let attrs = get_attrs(&file,table.classes()).unwrap();
+        let w = get_compound(&String::from("ar-ko"),&table,&get_compounding(&file)).unwrap();
+        let w = add_attr(w,get_attr(String::from("PL"),&attrs,table.classes()).unwrap(),&mut Vec::new());
+        assert_eq!(sc_input(&w),format!("ar-ko{}ta",phon::BOUNDARY));
+        assert_eq!(show(&w,&sc_input(&w)),"ar-ko-ta");
+    }
+
+    #[test]
+    fn melodies_follow_the_morphemes() {
+        let json = json!({
+            "vocab": {"sulat": {"gloss": "write", "class": "V", "tone": "L H"}},
+            "attributes": {
+                "CAUS": {"form": "pa", "pos": -2, "affects": ["V"], "tone": "H"},
+                "AV": {"form": "um", "pos": 1, "type": "infix", "anchor": "^[^aeiou]+", "affects": ["V"], "tone": "M"},
+                "LOC": {"form": "an", "pos": 2, "affects": ["V"], "tone": "L"}
+            }
+        });
+        let melody_of = |token : &str| match inflect(&json,token) {
+            Ok((w,_)) => melody(&w).join(" "),
+            Err(e) => panic!("{}: {}",token,e),
+        };
+        assert_eq!(melody_of("sulat"),"L H");
+        assert_eq!(melody_of("sulat+CAUS+LOC"),"H L H L");
+        assert_eq!(melody_of("sulat+AV"),"L M H");
+        assert_eq!(melody_of("sulat+LOC+AV+CAUS"),"H L M H L");
+    }
+
+    #[test]
+    fn only_plain_affixes_can_be_zero_morphemes() {
+        for kind in ["infix","circumfix","reduplication"] {
+            let json = json!({
+                "vocab": {"sulat": {"gloss": "write", "class": "V"}},
+                "attributes": {"ZZ": {"form": "um...an", "pos": 0, "type": kind, "anchor": "^s", "affects": ["V"]}}
+            });
+            let (file,_) = crate::langfile::parse(&json);
+            let problems : Vec<String> = crate::langfile::validate(&file).iter().map(|p| p.to_string()).collect();
+            assert!(problems.iter().any(|p| p.contains("attributes.ZZ.pos")),"{}: {:?}",kind,problems);
+        }
+    }
+
+    #[test]
+    fn an_infix_needs_its_anchor() {
+        let json = json!({
+            "vocab": {"sulat": {"gloss": "write", "class": "V"}, "basa": {"gloss": "read", "class": "V"}},
+            "attributes": {"AV": {"form": "um", "pos": 1, "type": "infix", "anchor": "^s", "affects": ["V"]}}
+        });
+        assert!(inflect(&json,"sulat+AV").is_ok());
+        match inflect(&json,"basa+AV") {
+            Ok(_) => panic!("basa+AV was accepted"),
+            Err(e) => assert_eq!(e.to_string(),"AV has nowhere to go in «basa», its anchor doesn't match!"),
+        }
+    }
+
+    fn pair(morphs : &str, gloss : &str) -> (String,String) {
+        (String::from(morphs),String::from(gloss))
+    }
+
+    #[test]
+    fn infixes_circumfixes_and_reduplication() {
+        let json = json!({
+            "vocab": {"sulat": {"gloss": "write", "class": "V"}, "seh": {"gloss": "see", "class": "V"}},
+            "attributes": {
+                "AV": {"form": "um", "pos": 1, "type": "infix", "anchor": "^[^aeiou]+", "affects": ["V"]},
+                "PTCP": {"form": "ge...en", "pos": 2, "type": "circumfix", "affects": ["V"]},
+                "RED": {"pos": -1, "type": "reduplication", "copy": "^[^aeiou]*[aeiou]", "affects": ["V"]},
+                "ITER": {"pos": 3, "type": "reduplication", "affects": ["V"]},
+                "CAUS": {"form": "pa", "pos": -2, "affects": ["V"]}
+            }
+        });
+        assert_eq!(lines(&json,"sulat+AV"),pair("s<um>ulat","<AV>write"));
+        assert_eq!(lines(&json,"seh+PTCP"),pair("ge-seh-en","PTCP-see-PTCP"));
+        assert_eq!(lines(&json,"sulat+RED"),pair("su~sulat","RED~write"));
+        assert_eq!(lines(&json,"sulat+ITER"),pair("sulat~sulat","write~ITER"));
+        // inner affixes are in place before the outer ones attach, and copies leave out the boundaries
+        assert_eq!(lines(&json,"sulat+CAUS+AV"),pair("pa-s<um>ulat","CAUS-<AV>write"));
+        assert_eq!(lines(&json,"sulat+AV+ITER"),pair("s<um>ulat~sumulat","<AV>write~ITER"));
+    }
+
+    fn number() -> Value {
+        json!({
+            "vocab": {"dog": {"gloss": "dog", "class": "N"}, "happy": {"gloss": "happy", "class": "M"}, "run": {"gloss": "run", "class": "V"}},
+            "attributes": {
+                "SG": {"form": "", "pos": 1, "affects": ["N"]},
+                "PL": {"form": "s", "pos": 1, "affects": ["N"]},
+                "GEN": {"form": "z", "pos": 2, "affects": ["N"]},
+                "NMLZ": {"form": "ness", "pos": 1, "derives": "N", "affects": ["M"]}
+            },
+            "features": {"number": {"values": ["SG","PL"], "default": "SG"}}
+        })
+    }
+
+    #[test]
+    fn unmarked_defaults_leave_no_boundary() {
+        let json = number();
+        assert_eq!(lines(&json,"dog"),pair("dog","dog.SG"));
+        assert_eq!(lines(&json,"dog+GEN"),pair("dog-z","dog.SG-GEN"));
+        assert_eq!(lines(&json,"dog+PL"),pair("dog-s","dog-PL"));
+        assert_eq!(lines(&json,"happy+NMLZ"),pair("happy-ness","(happy-NMLZ).SG"));
+    }
+
+    #[test]
+    fn features_take_one_value() {
+        let json = number();
+        match inflect(&json,"dog+SG+PL") {
+            Ok(_) => panic!("dog+SG+PL was accepted"),
+            Err(e) => assert_eq!(e.to_string(),"«dog» has more than one number: SG, PL!"),
+        }
+        // the default only goes where it applies
+        assert_eq!(lines(&json,"dog+SG"),pair("dog","dog.SG"));
+        assert_eq!(lines(&json,"run"),pair("run","run"));
+        // a derived word gets the defaults of its new class
+        assert_eq!(lines(&json,"happy+NMLZ+PL"),pair("happy-ness-s","(happy-NMLZ)-PL"));
+    }
+
+    #[test]
+    fn attributes_that_do_not_apply_are_ignored() {
+        match inflect(&number(),"run+PL") {
+            Ok((w,warnings)) => {
+                assert_eq!(warnings,vec![String::from("PL does not apply to «run», ignoring it")]);
+                assert_eq!(gloss(&w),"run");
+            },
+            Err(e) => panic!("{}",e),
+        }
+    }
+
+    #[test]
+    fn looking_up_glosses() {
+        let json = json!({
+            "vocab": {
+                "kat": {"gloss": "dog", "class": "N", "synonyms": ["hound"]},
+                "sal": {"gloss": "to run", "class": "V"},
+                "bot": {"gloss": "box", "class": "N"},
+                "bet": {"gloss": "box", "class": "V"}
+            }
+        });
+        let (file,_) = crate::langfile::parse(&json);
+        let table = LookupTable::new(&file).unwrap();
+        let found = |term : &str| table.lookup(term).map(|w| w.lemma.clone()).map_err(|e| e.to_string());
+        assert_eq!(found("dog"),Ok(String::from("kat")));
+        assert_eq!(found("Hound"),Ok(String::from("kat")));
+        assert_eq!(found("run"),Ok(String::from("sal")));
+        assert_eq!(found("box"),Err(String::from("«{box}» is ambiguous, candidates are: bet (box, V), bot (box, N)")));
+        assert_eq!(found("cat"),Err(String::from("No word glossed as «cat»!")));
+        assert_eq!(found(""),Err(String::from("Nothing to look up in «{}»!")));
+        assert_eq!(found(" "),Err(String::from("Nothing to look up in «{}»!")));
+    }
+
+    #[test]
+    fn irregular_forms() {
+        let json = json!({
+            "vocab": {
+                "mus": {"gloss": "mouse", "class": "N", "irregular": {"PL": "mis", "PL+GEN": "mises"}},
+                "gan": {"gloss": "go", "class": "V", "irregular": {"PST": "wen"}},
+                "gans": {"gloss": "goose", "class": "N", "irregular": {"PL": {"stem": "gens"}}}
+            },
+            "attributes": {
+                "PL": {"form": "s", "pos": 1, "affects": ["N"]},
+                "GEN": {"form": "z", "pos": 2, "affects": ["N"]},
+                "PST": {"form": "d", "pos": 1, "affects": ["V"]}
+            }
+        });
+        assert_eq!(lines(&json,"gan+PST"),pair("wen","go.PST"));
+        assert_eq!(lines(&json,"mus+PL"),pair("mis","mouse.PL"));
+        // the most specific form wins, whatever order the attributes come in
+        assert_eq!(lines(&json,"mus+GEN+PL"),pair("mises","mouse.PL.GEN"));
+        assert_eq!(lines(&json,"mus+GEN"),pair("mus-z","mouse-GEN"));
+        // an irregular stem still takes the regular affixes
+        assert_eq!(lines(&json,"gans+PL"),pair("gens-s","goose-PL"));
+        assert_eq!(lines(&json,"gans+PL+GEN"),pair("gens-s-z","goose-PL-GEN"));
+    }
+
+    #[test]
+    fn derivation_changes_the_class_and_starts_new_slots() {
+        let json = json!({
+            "vocab": {"happy": {"gloss": "happy", "class": "M"}, "dog": {"gloss": "dog", "class": "N"}},
+            "attributes": {
+                "COMP": {"form": "er", "pos": 1, "affects": ["M"]},
+                "NMLZ": {"form": "ness", "pos": 1, "derives": "N", "affects": ["M"]},
+                "PL": {"form": "s", "pos": 1, "affects": ["N"]},
+                "DU": {"form": "n", "pos": 1, "affects": ["N"]}
+            }
+        });
+        match inflect(&json,"happy+PL") {
+            Ok((_,warnings)) => assert_eq!(warnings,vec![String::from("PL does not apply to «happy», ignoring it")]),
+            Err(e) => panic!("{}",e),
+        }
+        assert_eq!(lines(&json,"happy+NMLZ+PL"),pair("happy-ness-s","(happy-NMLZ)-PL"));
+        // COMP and PL are both in slot 1, but of different words
+        assert_eq!(lines(&json,"happy+COMP+NMLZ+PL"),pair("happy-er-ness-s","(happy-COMP-NMLZ)-PL"));
+        match inflect(&json,"dog+PL+DU") {
+            Ok(_) => panic!("dog+PL+DU was accepted"),
+            Err(e) => assert_eq!(e.to_string(),"PL and DU both fill slot 1 of «dog»!"),
+        }
+    }
+}
@@ -1,30 +1,43 @@
 use std::{io::Result, /*io::prelude::*,*/ io::BufReader, fs::File, path::PathBuf,option::Option};
-use fltk::{app, button::Button, frame::Frame, prelude::*, window::Window, input::Input, };
+use fltk::{app, button::Button, frame::Frame, prelude::*, window::Window, input::Input, text::{TextBuffer,TextDisplay}, };
 use fltk_table::{SmartTable, TableOpts};
 //use arboard::Clipboard;
-use serde_json::{Value,json};
+use serde::Serialize;
+use serde_json::Value;
 use clap::Parser;
 use rfd::FileDialog;
+mod lang;
+mod langfile;
 mod lex;
 mod phon;
 
 /// A machine-based glosser designed for Conlangers
 #[derive(Parser)]
 struct Cli {
-  /// Print extra information about sound changes applied
+  /// Print the sound changes applied to each word on stderr
   #[clap(short,long)]
   verbose: bool,
+  /// (Command-line only) print the sound changes as JSON instead, implying --verbose
+  #[clap(long)]
+  json: bool,
   /// Use GUI instead of command-line
   #[clap(short,long)]
   graphical: bool,
   #[clap(long)]
   /// (Command-line only) print output as a TeX-formatted table instead of a '\t'-separated table
   tex: bool,
+  /// (Command-line only) stop the sound changes after the named block of rules
+  #[clap(long)]
+  stage: Option<String>,
   /// Default path to the JSON file containing the language information
   #[clap(short,parse(from_os_str))]
   file: Option<PathBuf>,
-  /// The expression to gloss, formatted as 'Lemma+ATTR+ATTR+...' or '{MetalangWord}+ATTR+ATTR'
+  /// The expression to gloss, formatted as 'Lemma+ATTR+ATTR+...' or '{MetalangWord}+ATTR+ATTR', with compounds written 'Lemma=Lemma+ATTR'.
+  /// Clitics declared in the language file are given like attributes
   pattern: Vec<String>,
+  /// Check the language file for mistakes instead of glossing, exiting with a non-zero status if any are found
+  #[clap(long)]
+  validate: bool,
 }
 
 struct GlossTable {
@@ -32,40 +45,91 @@ struct GlossTable {
     phonetic: Vec<String>,
     orthographic: Vec<String>,
     glosses: Vec<String>,
+    derivations: Vec<Derivation>,
+    warnings: Vec<String>,
     len: usize
 }
 
-fn get_gloss_info(toks: &Vec<String>, json: &Value, verbose: bool) -> Result<GlossTable> {
+// The sound changes that made one word's orthographic and phonetic forms
+#[derive(Serialize)]
+struct Derivation {
+    word: String,
+    input: String,
+    #[serde(skip)]
+    chain: String,
+    orthographic: Vec<phon::Step>,
+    phonetic: Vec<phon::Step>,
+}
+
+impl std::fmt::Display for Derivation {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.word, self.chain)?;
+        for (line,steps) in [("sc",&self.orthographic),("phonetic",&self.phonetic)] {
+            for s in steps {
+                let stage = s.stage.as_ref().map(|n| format!(" {}",n)).unwrap_or_default();
+                write!(f, "\n    [{}{}] {}: {} → {}", line, stage, s.rule, s.before, s.after)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn get_gloss_info(toks: &Vec<String>, lang: &lang::Language, stage: Option<&str>) -> Result<GlossTable> {
     let mut ws : Vec<lex::Word<_>> = Vec::new();
+    let mut warnings = Vec::new();
     for s in toks {
         let mut data = s.split("+");
         let root = String::from(Option::unwrap(data.next()));
         let xs : Vec<String> = data.map(String::from).collect();
-        let mut w = lex::get_word(&root,&json)?;
+        let mut w = lex::get_compound(&root,&lang.lookups,&lang.compounding)?;
         for x in xs {
-            let a = lex::get_attr(x,&json)?;
-            w = lex::add_attr(w,a);
+            if let Some(c) = lang.clitics.get(&x) {
+                w = lex::add_clitic(w,c.clone());
+                continue;
+            }
+            let a = lex::get_attr(x,&lang.attributes,lang.lookups.classes())?;
+            w = lex::add_attr(w,a,&mut warnings);
         }
+        w = lex::apply_features(w,&lang.features,&lang.attributes,lang.lookups.classes())?;
+        lex::check_slots(&w)?;
+        lex::check_infixes(&w)?;
         ws.push(w);
     }
     let mut inflections = Vec::new();
     let mut orthographic = Vec::new();
     let mut phonetic = Vec::new();
     let mut glosses = Vec::new();
-    for w in &ws {
-        inflections.push(lex::inflect(w));
-        let orth = phon::to_orthography(inflections[inflections.len()-1].clone(),&json["sc"],&json["cats"],&json["multigraphs"],verbose);
-        orthographic.push(orth);
-        phonetic.push(phon::to_orthography(orthographic[orthographic.len()-1].clone(),&json["phonetic"],&json["cats"],&json!(null),verbose));
+    let mut derivations = Vec::new();
+    // stopping partway through `sc` leaves nothing for the phonetic rules to do
+    let sc_stage = stage.map(|s| lang.orthography.stages().contains(&s)).unwrap_or(false);
+    for (tok,w) in toks.iter().zip(&ws) {
+        let morphs = phon::harmonize(lex::sc_input(w),&lang.harmony);
+        inflections.push(lex::show(w,&morphs));
+        let (orth,orth_steps) = phon::to_orthography(morphs.clone(),&lang.orthography,stage);
+        let marked = phon::prosody(&orth,&lang.prosody,&lang.orthography,lex::stress(w),&lex::melody(w));
+        let (mut phone,phone_steps) = if sc_stage { (marked,Vec::new()) } else { phon::to_orthography(marked,&lang.phonetic,stage) };
+        if lang.show_syllables {
+            phone = phon::show_syllables(&phone,&lang.phonetic);
+        }
+        orthographic.push(lex::thaw(w,phon::surface(&orth)));
+        phonetic.push(lex::thaw(w,phon::surface(&phone)));
         glosses.push(lex::gloss(w));
+        // hidden linkers are put back in every form the trace shows
+        let thaw_steps = |steps : Vec<phon::Step>| -> Vec<phon::Step> {
+            steps.into_iter().map(|s| phon::Step { before : lex::thaw(w,s.before), after : lex::thaw(w,s.after), surface : lex::thaw(w,s.surface), ..s }).collect()
+        };
+        let (orth_steps,phone_steps) = (thaw_steps(orth_steps),thaw_steps(phone_steps));
+        let steps : Vec<phon::Step> = orth_steps.iter().chain(&phone_steps).cloned().collect();
+        let chain = phon::derivation(&lex::thaw(w,morphs.clone()),&steps);
+        derivations.push(Derivation { word : tok.clone(), input : lex::show(w,&morphs), chain, orthographic : orth_steps, phonetic : phone_steps });
     }
     let len = (&glosses).len();
-    Ok(GlossTable { inflections, orthographic, phonetic, glosses, len })
+    Ok(GlossTable { inflections, orthographic, phonetic, glosses, derivations, warnings, len })
 }
 
-fn start_gui(path : PathBuf, json : Value, verbose : bool) -> Result<()> {
+fn start_gui(path : PathBuf, lang : lang::Language) -> Result<()> {
     let app = app::App::default();
-    let mut wind = Window::new(100, 100, 600, 300, "Hello from rust");
+    let mut wind = Window::new(100, 100, 600, 460, "Hello from rust");
     let _frame = Frame::new(20, 0, 400, 50, "Enter gloss string:");
     let text = Input::new(20, 50, 360, 30, "");
     let mut stat_frame = Frame::new(400, 0, 200, 180, "");
@@ -79,19 +143,21 @@ fn start_gui(path : PathBuf, json : Value, verbose : bool) -> Result<()> {
                     ..Default::default()
                 });
     table.end();
+    let mut trace = TextDisplay::new(10, 310, 580, 140, "Sound changes");
+    let mut trace_buf = TextBuffer::default();
+    trace.set_buffer(trace_buf.clone());
     wind.end();
 
-    // TODO populate with actual data
     let stats = format!("File: {}\n{} lexemes, {} attributes.\n{} sound change rules.",
                         path.as_path().file_name().unwrap().to_str().unwrap(),
-                        69,69,69);
+                        lang.lookups.len(),lang.attributes.len(),lang.orthography.len());
     stat_frame.set_label(stats.as_str());
 
     wind.show();
     gb.set_callback(move |_| {
         let raw = &text.value();
         let split = raw.split(" ").map(|s| String::from(s)).collect();
-        match get_gloss_info(&split, &json, verbose) {
+        match get_gloss_info(&split, &lang, None) {
             Err(e) => { 
                 eprintln!("You done fucked up!\n{:?}",e);
                 table.set_label("We do a little trolling.");
@@ -110,7 +176,13 @@ fn start_gui(path : PathBuf, json : Value, verbose : bool) -> Result<()> {
                     table.set_cell_value(2, j, &gt.inflections[i]);
                     table.set_cell_value(3, j, &gt.glosses[i]);
                 }
-                table.set_label("We engage in a nontrivial quantity of shenanigans.");
+                let derivations : Vec<String> = gt.derivations.iter().map(|d| d.to_string()).collect();
+                trace_buf.set_text(&derivations.join("\n"));
+                if gt.warnings.is_empty() {
+                    table.set_label("We engage in a nontrivial quantity of shenanigans.");
+                } else {
+                    table.set_label(&gt.warnings.join("\n"));
+                }
             },
         }
     });
@@ -118,6 +190,32 @@ fn start_gui(path : PathBuf, json : Value, verbose : bool) -> Result<()> {
     Ok(())
 }
 
+fn validate(path : &PathBuf, json : &Value) -> ! {
+    let (file,_) = langfile::parse(json);
+    for w in langfile::lint(json,&file) {
+        println!("warning: {}",w);
+    }
+    match lang::Language::from_json(json) {
+        Ok(lang) => {
+            if lang.lookups.is_empty() {
+                println!("warning: no vocab, so there is nothing to gloss");
+            }
+            if lang.orthography.is_empty() && lang.phonetic.is_empty() {
+                println!("warning: no \"sc\" or \"phonetic\" rules, so every form is spelled as in the morpheme line");
+            }
+            println!("{}: OK ({} lexemes, {} attributes, {} sound change rules)",
+                     path.display(),lang.lookups.len(),lang.attributes.len(),lang.orthography.len());
+            std::process::exit(0);
+        },
+        Err(e) => {
+            for line in e.to_string().lines() {
+                println!("error: {}",line);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let args = Cli::parse();
     let raw_args : Vec<_> = std::env::args().collect();
@@ -129,13 +227,46 @@ fn main() -> Result<()> {
     };
     let f2 = File::open(path.clone())?;
     let reader2 = BufReader::new(f2);
-    let json : Value = serde_json::from_reader(reader2)?;
+    let json : Value = match serde_json::from_reader(reader2) {
+        Ok(json) => json,
+        Err(e) => {
+            eprintln!("{} is not valid JSON: {}",path.display(),e);
+            std::process::exit(1);
+        }
+    };
+    if args.validate {
+        validate(&path, &json);
+    }
+    let lang = match lang::Language::from_json(&json) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Problems found in {}:\n{}",path.display(),e);
+            std::process::exit(1);
+        }
+    };
 
     if args.graphical || (raw_args.len() <= 1) {
-        start_gui(path,json,args.verbose)
+        start_gui(path,lang)
     }
     else {
-        let gt = get_gloss_info(&args.pattern, &json, args.verbose)?;
+        if let Some(s) = &args.stage {
+            let stages : Vec<&str> = lang.orthography.stages().into_iter().chain(lang.phonetic.stages()).collect();
+            if !stages.contains(&s.as_str()) {
+                eprintln!("No stage named \"{}\" in {} (stages: {})",s,path.display(),stages.join(", "));
+                std::process::exit(1);
+            }
+        }
+        let gt = get_gloss_info(&args.pattern, &lang, args.stage.as_deref())?;
+        for w in &gt.warnings {
+            eprintln!("warning: {}",w);
+        }
+        if args.json {
+            eprintln!("{}",serde_json::to_string_pretty(&gt.derivations)?);
+        } else if args.verbose {
+            for d in &gt.derivations {
+                eprintln!("{}",d);
+            }
+        }
         if args.tex {
             println!("\\begin{{tabular}}{{{}}}","l".repeat(gt.len));
             println!("\\textbf{{{}}}\\\\",gt.orthographic.join("}&\\textbf{"));
@@ -152,3 +283,27 @@ fn main() -> Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn traces_show_frozen_linkers() {
+        let json = json!({
+            "vocab": {
+                "kat": {"gloss": "dog", "class": "N"},
+                "bot": {"gloss": "box", "class": "N"}
+            },
+            "compounding": {"linker": "e", "sound_changes": false},
+            "sc": ["a -> ɑ / _", "e -> ∅ / _"]
+        });
+        let lang = lang::Language::from_json(&json).unwrap();
+        let gt = get_gloss_info(&vec![String::from("kat=bot")],&lang,None).unwrap();
+        let d = &gt.derivations[0];
+        assert_eq!(d.chain,"*katebot > kɑtebot");
+        assert_eq!((d.orthographic[0].before.as_str(),d.orthographic[0].after.as_str()),("kat-e-bot","kɑt-e-bot"));
+        assert_eq!(gt.orthographic[0],"kɑtebot");
+    }
+}
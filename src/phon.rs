@@ -1,5 +1,7 @@
-use serde_json::Value;
+use std::collections::{BTreeMap,BTreeSet,HashMap};
 use regex::Regex;
+use serde::Serialize;
+use crate::langfile::{StressEntry,StressConfig,ToneConfig,RuleEntry,HarmonyEntry};
 //use std::borrow::Cow;
 
 /**
@@ -8,27 +10,222 @@ use regex::Regex;
  */
 struct Category {
     id:String,
-    seqs:Vec<String>,
+    seqs:Vec<String>,    // longest first, so the alternation prefers multigraphs
+    members:Vec<String>, // as listed, for replacing by index
+}
+
+// Phonemes described by their distinctive features (the `phonemes` section), so rules can refer to
+// natural classes like `[+voice -son]` instead of listing their members
+struct Inventory {
+    phonemes:Vec<(String,BTreeMap<String,bool>)>, // longest first, like category members
+    features:BTreeSet<String>,
+}
+
+impl Inventory {
+    fn new(phonemes : &BTreeMap<String,BTreeMap<String,String>>) -> Inventory {
+        let mut list : Vec<(String,BTreeMap<String,bool>)> = phonemes.iter()
+            .map(|(p,fs)| (p.clone(),fs.iter().map(|(f,v)| (f.clone(),v == "+")).collect()))
+            .collect();
+        list.sort_by_key(|(p,_)| std::cmp::Reverse(p.len()));
+        let features = phonemes.values().flat_map(|fs| fs.keys().cloned()).collect();
+        Inventory { phonemes : list, features }
+    }
+
+    // Reads the inside of `[...]` as a feature specification, if it looks like one. Once there is a
+    // `phonemes` section, anything shaped like `[+name -name]` is one, so a misspelt feature is reported
+    // rather than quietly read as a regex class.
+    fn spec(&self, s : &str) -> Option<Result<Vec<(String,bool)>,String>> {
+        let mut spec = Vec::new();
+        for tok in s.split_whitespace() {
+            let value = match tok.chars().next() {
+                Some('+') => true,
+                Some('-') => false,
+                _ => return None,
+            };
+            let name = &tok[1..];
+            if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return None;
+            }
+            spec.push((String::from(name),value));
+        }
+        if spec.is_empty() || self.features.is_empty() {
+            return None;
+        }
+        match spec.iter().find(|(f,_)| !self.features.contains(f)) {
+            Some((f,_)) => Some(Err(format!("unknown phonological feature \"{}\"",f))),
+            None => Some(Ok(spec)),
+        }
+    }
+
+    fn matching(&self, spec : &Vec<(String,bool)>) -> Vec<&String> {
+        self.phonemes.iter().filter(|(_,fs)| spec.iter().all(|(f,v)| fs.get(f) == Some(v))).map(|(p,_)| p).collect()
+    }
+
+    // Each phoneme paired with the one that differs from it only as the spec says, where there is one
+    fn changes(&self, spec : &Vec<(String,bool)>) -> HashMap<String,String> {
+        let mut out = HashMap::new();
+        for (p,fs) in &self.phonemes {
+            let mut want = fs.clone();
+            for (f,v) in spec { want.insert(f.clone(),*v); }
+            if let Some((q,_)) = self.phonemes.iter().find(|(_,gs)| *gs == want) {
+                out.insert(p.clone(),q.clone());
+            }
+        }
+        out
+    }
+}
+
+// Replace each natural class in a regex with an alternation of its members.
+// Errors give the offset (in characters) of the class.
+fn natural_classes(s : &str, inv : &Inventory) -> Result<String,(usize,String)> {
+    let chars : Vec<char> = s.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                out.extend(&chars[i..(i+2).min(chars.len())]);
+                i += 2;
+            },
+            '[' => {
+                let close = chars[i..].iter().position(|c| *c == ']').map(|j| i + j);
+                let inner : Option<String> = close.map(|j| chars[i+1..j].iter().collect());
+                match (close,inner.as_deref().and_then(|x| inv.spec(x))) {
+                    (Some(j),Some(Ok(spec))) => {
+                        let members : Vec<String> = inv.matching(&spec).iter().map(|p| regex::escape(p)).collect();
+                        // a class with no members can't match anything
+                        let alt = if members.is_empty() { String::from("[^\\s\\S]") } else { members.join("|") };
+                        out = out + "(?:" + &alt + ")";
+                        i = j + 1;
+                    },
+                    (_,Some(Err(e))) => return Err((i,e)),
+                    _ => {
+                        out.push('[');
+                        i += 1;
+                    },
+                }
+            },
+            c => {
+                out.push(c);
+                i += 1;
+            },
+        }
+    }
+    Ok(out)
+}
+
+// What a match of the target is replaced with
+enum Replacement {
+    Text(String),                 // `$1` etc. refer to groups in the target
+    Map(HashMap<String,String>),  // each matching segment is swapped for its counterpart; others are left alone
+    Geminate,                     // `X > X²`
+    Degeminate,                   // `X² > X`, keeping the first half of the match
+    Metathesis,                   // `\\`, reversing the order of the matched segments
 }
 
 struct SCRule {
+    source:String,
     target:Regex,
-    replacement:String,
+    replacement:Replacement,
     pos_env:(Regex,Regex),
-    neg_env:(Regex,Regex),
+    neg_env:Option<(Regex,Regex)>,
+    boundaries:bool, // whether the rule mentions `+`; if not, morpheme boundaries are invisible to it
+    prosodic:bool,   // whether the rule mentions stress or tone marks; if not, they're invisible to it too
+    syllables:bool,  // whether the rule mentions `$`, and so needs the word syllabified first
+}
+
+// How the rules of a block are applied
+#[derive(Clone,Copy,Default)]
+struct Mode {
+    sequential:bool, // each application sees the output of the ones before it
+    rtl:bool,        // matches are looked for from the end of the word
+    iterate:bool,    // each rule is reapplied until the word stops changing
+}
+
+// Rules given on their own in the file are blocks of one, with no name
+struct Block {
+    name:Option<String>,
+    rules:Vec<SCRule>,
+    mode:Mode,
+}
+
+// A rule list compiled once per language file, along with the multigraphs it was compiled against
+pub struct SoundChanges {
+    blocks:Vec<Block>,
+    multigraphs:Vec<Regex>,
+    syllable:Option<Syllables>,
+}
+
+// A syllable template (see `compile_template`), and the part of it that's the nucleus
+pub struct Syllables {
+    template:Regex,
+    nucleus:Option<Regex>,
+}
+
+impl SoundChanges {
+    pub fn len(&self) -> usize {
+        self.blocks.iter().map(|b| b.rules.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Names of the blocks, in the order they run
+    pub fn stages(&self) -> Vec<&str> {
+        self.blocks.iter().filter_map(|b| b.name.as_deref()).collect()
+    }
 }
 
 impl std::fmt::Display for SCRule {
     fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let neg = if self.neg_env.0.as_str().chars().count() == 0
-                    && self.neg_env.1.as_str().chars().count() == 0
-                {
-                    String::new()
-                } else {
-                    format!(" /! {}_{}", &self.neg_env.0.as_str(), &self.neg_env.1.as_str())
-                };
-        write!(f, "`{}` → `{}` / {}_{}{}", &self.target, &self.replacement, &self.pos_env.0, &self.pos_env.1, neg)
+        write!(f, "{}", &self.source)
+    }
+}
+
+// Morpheme boundaries (`-`, `=` etc. in the morpheme line, `+` in rules) are this character from `lex::sc_input`
+// on, so that a `-` in a form or a replacement is just a hyphen
+pub const BOUNDARY : char = '\u{F8FF}';
+
+// Syllable boundaries, inserted by `syllabify` for rules that mention `$`
+const SYLLABLE : char = '\u{F8FD}';
+
+// `#` in an environment is the edge of a word: the start or end of the token, or a space within it
+// (lemmas may contain spaces). Anchors are spelled out here since `^` and `$` are easy to forget.
+// Where the file declares a syllable template, `$` is the edge of a syllable, which words' edges are too;
+// otherwise it's left as the regex anchor.
+const WORD_START : &str = "(?:^| )";
+const WORD_END : &str = "(?:$| )";
+const SYLLABLE_START : &str = "(?:^| |\u{F8FD})";
+const SYLLABLE_END : &str = "(?:$| |\u{F8FD})";
+
+// Which part of a rule a regex comes from, which decides what `#` and `$` stand for
+#[derive(Clone,Copy)]
+enum Side { Left, Target, Right }
+
+// Replace each unescaped `+` in a rule with the boundary character, and each `#` (and `$`, if the rules are
+// syllabified) with the anchor for that side
+fn mark_boundaries(s : &str, side : Side, syllabic : bool) -> String {
+    let (word,syllable) = match side {
+        Side::Left => (WORD_START,SYLLABLE_START),
+        Side::Target => ("#","\u{F8FD}"),
+        Side::Right => (WORD_END,SYLLABLE_END),
+    };
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                out.push(c);
+                if let Some(d) = chars.next() { out.push(d); }
+            },
+            '+' => out.push(BOUNDARY),
+            '#' => out.push_str(word),
+            '$' if syllabic => out.push_str(syllable),
+            _ => out.push(c),
+        }
     }
+    out
 }
 
 fn multigraph_to_unigraph<'a>(s : &'a str, multigraphs : &'a Vec<Regex>) -> String {
@@ -51,179 +248,1474 @@ fn unigraph_to_multigraph<'a>(s : &'a str, multigraphs : &'a Vec<Regex>) -> Stri
     // For now, we will naively assume there are fewer than 6400 elements in this list.
     // and that people won't be using custom fonts
     for mg in multigraphs {
-        let mut seq = String::new();
-        seq.push(unigraph);
-        let r = Regex::new(&seq).unwrap();
-        out = String::from(r.replace_all(&out,mg.as_str()));
+        out = out.replace(unigraph,mg.as_str());
         unigraph = char::from_u32((unigraph as u32) + 1).unwrap();
     }
     out
 }
 
-fn from_cats(rule : SCRule, cats : &Vec<Category>, multigraphs : &Vec<Regex>, _verb : bool) -> SCRule {
-    let mut rule_new = rule;
-    for cat in cats {
-        // replace each `@C` category abbreviation with the regular (c1|c2|c3|...) form
-        let get = Regex::new(&cat.id).unwrap();
-        let seqs = String::from("(") + &cat.seqs.join("|") + ")";
-        let pos_l_repl = get.replace_all(rule_new.pos_env.0.as_str(),&seqs);
-        rule_new.pos_env.0 = Regex::new(&pos_l_repl).unwrap();
-        let pos_r_repl = get.replace_all(rule_new.pos_env.1.as_str(),&seqs);
-        rule_new.pos_env.1 = Regex::new(&pos_r_repl).unwrap();
-        let neg_l_repl = get.replace_all(rule_new.neg_env.0.as_str(),&seqs);
-        rule_new.neg_env.0 = Regex::new(&neg_l_repl).unwrap();
-        let neg_r_repl = get.replace_all(rule_new.neg_env.1.as_str(),&seqs);
-        rule_new.neg_env.1 = Regex::new(&neg_r_repl).unwrap();
-        let target_repl = get.replace_all(rule_new.target.as_str(),&seqs);
-        rule_new.target = Regex::new(&target_repl).unwrap();
+// Rebuild every regex in the rule from its rewritten source
+fn map_regexes(rule : SCRule, f : &dyn Fn(&str) -> Result<String,String>) -> Result<SCRule,String> {
+    let pair = |(l,r) : (Regex,Regex)| -> Result<(Regex,Regex),String> { Ok((rx(&f(l.as_str())?)?,rx(&f(r.as_str())?)?)) };
+    Ok(SCRule {
+        target : rx(&f(rule.target.as_str())?)?,
+        pos_env : pair(rule.pos_env)?,
+        neg_env : rule.neg_env.map(pair).transpose()?,
+        ..rule
+    })
+}
+
+// Replace each `@name` with the alternation of its category's sequences
+fn expand_cats(r : &str, cats : &Vec<Category>) -> String {
+    let mut out = String::new();
+    let mut last = 0;
+    for (i,name) in cat_refs(r) {
+        if let Some(cat) = category(name,cats) {
+            out = out + &r[last..i] + "(" + &cat.seqs.join("|") + ")";
+            last = i + name.len();
+        }
+    }
+    out + &r[last..]
+}
+
+// The category s is the id of, if it's exactly one `@name`
+fn category<'a>(s : &str, cats : &'a Vec<Category>) -> Option<&'a Category> {
+    match cat_refs(s)[..] {
+        [(0,name)] if name == s => cats.iter().find(|c| c.id == name),
+        _ => None,
     }
+}
+
+fn from_cats(rule : SCRule, cats : &Vec<Category>, multigraphs : &Vec<Regex>) -> Result<SCRule,String> {
+    // replace each `@C` category abbreviation with the regular (c1|c2|c3|...) form
+    let mut rule_new = map_regexes(rule,&|r| Ok(expand_cats(r,cats)))?;
     // replace each multigraph 
-    let pos_l_repl = multigraph_to_unigraph(rule_new.pos_env.0.as_str(),multigraphs);
-    rule_new.pos_env.0 = Regex::new(&pos_l_repl).unwrap();
-    let pos_r_repl = multigraph_to_unigraph(rule_new.pos_env.1.as_str(),multigraphs);
-    rule_new.pos_env.1 = Regex::new(&pos_r_repl).unwrap();
-    let neg_l_repl = multigraph_to_unigraph(rule_new.neg_env.0.as_str(),multigraphs);
-    rule_new.neg_env.0 = Regex::new(&neg_l_repl).unwrap();
-    let neg_r_repl = multigraph_to_unigraph(rule_new.neg_env.1.as_str(),multigraphs);
-    rule_new.neg_env.1 = Regex::new(&neg_r_repl).unwrap();
-    let target_repl = multigraph_to_unigraph(rule_new.target.as_str(),multigraphs);
-    rule_new.target = Regex::new(&target_repl).unwrap();
-    let repstr_repl = multigraph_to_unigraph(&rule_new.replacement,multigraphs);
-    rule_new.replacement = String::from(repstr_repl);
-    // if _verb { println!("Multigraph «{}» mapped to '{}' U+{:x}",multigraph.as_str(),&seq,unigraph as u32); }
-    rule_new
-}
-
-fn sca(token : &str, rule : SCRule, verb : bool) -> String {
-    if verb {println!("Evaluating {}->{}/{}_{} on «{}»",rule.target.as_str(),&rule.replacement,rule.pos_env.0.as_str(),rule.pos_env.1.as_str(),&token);}
-    let mut out = String::new();
-    let mut no_mat = rule.target.split(&token);
-    let mut prev = no_mat.next().unwrap();
-    let mut full_prev = String::from(prev.clone());
-    let full = String::from(token.clone());
-    for mat in rule.target.find_iter(&token) {
-        let mut update = false;
-        for l_env in rule.pos_env.0.find_iter(&full_prev) {
-            let l_algt =
-                    if l_env.as_str().chars().count() > 0 {
-                        l_env.end()
-                    } else {
-                        mat.start()
-                    };
-            let next = match &mat.as_str().as_bytes().len() + &full_prev.as_bytes().len() {
-                    0 => full.clone(),
-                    x => {
-                        //println!("{}, |mat|={}",&full_prev,mat.as_str().chars().count());
-                        if x<full.chars().count() {
-                            String::from(full.split_at(x).1)
-                        } else {
-                            String::new()
-                        }
+    rule_new = map_regexes(rule_new,&|r| Ok(multigraph_to_unigraph(r,multigraphs)))?;
+    rule_new = map_regexes(rule_new,&geminates)?;
+    rule_new.replacement = match rule_new.replacement {
+        Replacement::Text(t) => Replacement::Text(multigraph_to_unigraph(&t,multigraphs)),
+        Replacement::Map(m) => Replacement::Map(m.iter()
+            .map(|(k,v)| (multigraph_to_unigraph(k,multigraphs),multigraph_to_unigraph(v,multigraphs)))
+            .collect()),
+        r => r,
+    };
+    Ok(rule_new)
+}
+
+// Where the regex atom ending `out` starts: a single (possibly escaped) character or a group
+fn atom_start(out : &Vec<char>) -> Result<usize,String> {
+    let n = out.len();
+    match out.last() {
+        None => Err(String::from("\"²\" needs something to double")),
+        Some(_) if n >= 2 && out[n-2] == '\\' => Ok(n - 2),
+        Some(']') => Err(String::from("\"²\" can't follow a character class; use a category or natural class")),
+        Some(')') => {
+            let mut depth = 0;
+            for i in (0..n).rev() {
+                let escaped = i > 0 && out[i-1] == '\\';
+                match out[i] {
+                    ')' if !escaped => depth += 1,
+                    '(' if !escaped => {
+                        depth -= 1;
+                        if depth == 0 { return Ok(i); }
                     },
-                };
-            let r_env_start = match rule.pos_env.1.find(&next) {
-                        Some(r) => r.start(),
-                        None => token.chars().count(),
-                        // usize is non-negative, but it really just needs to be nonzero
-                };
-            if verb {println!("Test: {}#{}; r_env:{}",&full_prev,next, r_env_start);}
-            if verb {println!("l={}, match={} @{}",rule.pos_env.0.as_str(),l_env.as_str(),l_algt);}
-            if verb {println!("r={}, match={} @{}",rule.pos_env.1.as_str(),
-                    match rule.pos_env.1.find(&next) {Some(m)=>m.as_str(),None=>("NONE"),},r_env_start);}
-            if (mat.start() == l_algt) && (r_env_start == 0) {
-                let replaced = rule.target.replace(mat.as_str(),&rule.replacement);
-                let r_neg = match rule.neg_env.1.find(&next) {
-                    Some(r) => r,
-                    None => Regex::new(r"").unwrap().find("").unwrap()
-                };
-                // Go thru ALL possible negative environments, if NONE match,
-                for l_neg in rule.neg_env.0.find_iter(&full_prev) {
-                    if verb {println!("Match! neg={}_{}",l_neg.as_str(),r_neg.as_str())}
-                    match (l_neg.as_str().chars().count(),r_neg.as_str().chars().count(),
-                            mat.start() == l_neg.end()+out.chars().count(),
-                            r_neg.start() == 0) {
-                        (0,0,_,_) => {update=true;out = out + &prev + &replaced;break},
-                        (0,_,_,true) => {update=true;out = out + &prev + &mat.as_str();break},
-                        (_,0,true,_) => {update=true;out = out + &prev + &mat.as_str();break},
-                        (_,_,true,true) => {update=true;out = out + &prev + &mat.as_str();break},
-                        _ => (),
-                    };
-                }
-                if update {
-                    break;
-                } else {
-                    match (r_neg.as_str().chars().count(),
-                            r_neg.start() == 0) {
-                        (0,_) => {update=true;out = out + &prev + &replaced;break},
-                        (_,true) => {update=true;out = out + &prev + &mat.as_str();break},
-                        _ => {update=true;out = out + &prev + &replaced;break},
-                    };
+                    _ => (),
                 }
             }
+            Err(String::from("unbalanced parentheses before \"²\""))
+        },
+        Some(_) => Ok(n - 1),
+    }
+}
+
+// The top-level alternatives of a group's contents
+fn alternatives(s : &str) -> Vec<String> {
+    let mut out = vec![String::new()];
+    let mut depth = 0;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                out.last_mut().unwrap().push(c);
+                if let Some(d) = chars.next() { out.last_mut().unwrap().push(d); }
+                continue;
+            },
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            '|' if depth == 0 => {
+                out.push(String::new());
+                continue;
+            },
+            _ => (),
+        }
+        out.last_mut().unwrap().push(c);
+    }
+    out
+}
+
+// `X²` matches a geminate X, where X is a segment, a category or a natural class. Regexes can't
+// refer back to what they matched, so each alternative is doubled instead: `(p|t)²` is `(?:pp|tt)`.
+fn geminates(s : &str) -> Result<String,String> {
+    let mut out : Vec<char> = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                out.push(c);
+                if let Some(d) = chars.next() { out.push(d); }
+            },
+            '²' => {
+                let start = atom_start(&out)?;
+                let atom : String = out.drain(start..).collect();
+                let doubled = match atom.strip_prefix('(').and_then(|a| a.strip_suffix(')')) {
+                    Some(inner) => {
+                        let inner = inner.strip_prefix("?:").unwrap_or(inner);
+                        let alts : Vec<String> = alternatives(inner).iter().map(|a| format!("(?:{})(?:{})",a,a)).collect();
+                        format!("(?:{})",alts.join("|"))
+                    },
+                    None => atom.repeat(2),
+                };
+                out.extend(doubled.chars());
+            },
+            _ => out.push(c),
+        }
+    }
+    Ok(out.into_iter().collect())
+}
+
+// Environments are checked against everything before and after the target, so they're anchored there
+fn anchor(rule : SCRule) -> Result<SCRule,String> {
+    let pair = |(l,r) : (Regex,Regex)| -> Result<(Regex,Regex),String> {
+        Ok((rx(&format!("(?:{})$",l.as_str()))?,rx(&format!("^(?:{})",r.as_str()))?))
+    };
+    Ok(SCRule {
+        pos_env : pair(rule.pos_env)?,
+        neg_env : rule.neg_env.map(pair).transpose()?,
+        ..rule
+    })
+}
+
+// Whether the rule applies to a match of its target at s[start..end]
+fn applies(rule : &SCRule, s : &str, start : usize, end : usize) -> bool {
+    let (before,after) = (&s[..start],&s[end..]);
+    if !(rule.pos_env.0.is_match(before) && rule.pos_env.1.is_match(after)) {
+        return false;
+    }
+    match &rule.neg_env {
+        Some((l,r)) => !(l.is_match(before) && r.is_match(after)),
+        None => true,
+    }
+}
+
+// s[start..end] is replaced by text
+struct Edit {
+    start:usize,
+    end:usize,
+    text:String,
+}
+
+fn next_char(s : &str, i : usize) -> usize {
+    s[i..].chars().next().map(|c| i + c.len_utf8()).unwrap_or(s.len() + 1)
+}
+
+// Each segment of s that has a counterpart in pairs swapped for it, the longest segment first, so that
+// `[+voice -son]a -> [-voice]` devoices the consonant and keeps the vowel
+fn swap_segments(s : &str, pairs : &HashMap<String,String>) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        match pairs.keys().filter(|k| !k.is_empty() && rest.starts_with(k.as_str())).max_by_key(|k| k.len()) {
+            Some(k) => {
+                out.push_str(&pairs[k]);
+                rest = &rest[k.len()..];
+            },
+            None => {
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            },
+        }
+    }
+    out
+}
+
+// What a match of the rule's target is replaced with
+fn edit(rule : &SCRule, caps : &regex::Captures) -> Edit {
+    let m = caps.get(0).unwrap();
+    let mut text = String::new();
+    match &rule.replacement {
+        Replacement::Text(t) => caps.expand(t,&mut text),
+        Replacement::Map(pairs) => text = swap_segments(m.as_str(),pairs),
+        Replacement::Geminate => text = m.as_str().repeat(2),
+        Replacement::Degeminate => {
+            let n = m.as_str().chars().count();
+            text = m.as_str().chars().take(n.div_ceil(2)).collect();
+        },
+        // multigraphs are single characters by now, so this keeps them together
+        Replacement::Metathesis => text = m.as_str().chars().rev().collect(),
+    }
+    Edit { start : m.start(), end : m.end(), text }
+}
+
+// Every place the rule applies, found left to right on the input (so applications don't feed each other)
+fn edits(s : &str, rule : &SCRule) -> Vec<Edit> {
+    let mut out = Vec::new();
+    let mut at = 0;
+    while at <= s.len() {
+        let caps = match rule.target.captures_at(s,at) {
+            Some(c) => c,
+            None => break,
+        };
+        let m = caps.get(0).unwrap();
+        if applies(rule,s,m.start(),m.end()) {
+            out.push(edit(rule,&caps));
+            at = if m.end() > m.start() { m.end() } else { next_char(s,m.end()) };
+        } else {
+            // a later match could overlap this one
+            at = next_char(s,m.start());
         }
-        if !update {
-            out = out + &prev + &mat.as_str();
+    }
+    out
+}
+
+// The application of the rule whose match starts exactly at p, if there is one
+fn edit_at(s : &str, rule : &SCRule, p : usize) -> Option<Edit> {
+    let caps = rule.target.captures_at(s,p)?;
+    let m = caps.get(0).unwrap();
+    if m.start() == p && applies(rule,s,m.start(),m.end()) { Some(edit(rule,&caps)) } else { None }
+}
+
+// As `edits`, but where matches overlap the rightmost one wins
+fn edits_rtl(s : &str, rule : &SCRule) -> Vec<Edit> {
+    let mut out = Vec::new();
+    let mut limit = s.len();
+    for p in (0..=s.len()).rev().filter(|p| s.is_char_boundary(*p)) {
+        match edit_at(s,rule,p) {
+            Some(e) if e.end <= limit => {
+                limit = e.start;
+                out.push(e);
+            },
+            _ => (),
         }
-        prev = no_mat.next().unwrap();
-        full_prev = full_prev + &mat.as_str() + &prev;
     }
-    out + &prev
+    out.reverse();
+    out
 }
 
-pub fn to_orthography(token : String, sc : &Value, cats : &Value, multigraphs : &Value, verbose : bool) -> String {
-    let rules =
-        match sc {
-            Value::Array(rs) => rs.iter().map(|s| s.as_str()).collect(),
-            _ => Vec::<_>::new(),
+// The rule's output, and its applications as batches of edits, each batch made to the output of the one
+// before. Sequential applications are made one at a time, so each one can feed (or bleed) the next.
+fn run(s : &str, rule : &SCRule, mode : Mode) -> (String,Vec<Vec<Edit>>) {
+    if !mode.sequential {
+        let es = if mode.rtl { edits_rtl(s,rule) } else { edits(s,rule) };
+        return (apply(s,&es),vec![es]);
+    }
+    let mut cur = String::from(s);
+    let mut batches = Vec::new();
+    // positions before `at` (after it, right to left) have already been looked at
+    let mut at = if mode.rtl { s.len() } else { 0 };
+    loop {
+        let found = if mode.rtl {
+            (0..=at.min(cur.len())).rev().filter(|p| cur.is_char_boundary(*p)).find_map(|p| edit_at(&cur,rule,p))
+        } else {
+            (at..=cur.len()).filter(|p| cur.is_char_boundary(*p)).find_map(|p| edit_at(&cur,rule,p))
         };
-    let mg_rep =
-        match multigraphs {
-            Value::Array(ms) => ms.iter().map(|mg| Regex::new(mg.as_str().unwrap()).unwrap()).collect(),
-            _ => Vec::<_>::new(),
+        let e = match found {
+            Some(e) => e,
+            None => break,
         };
-    let mut cat_vec : Vec<Category> = Vec::new();
-    match cats {
-        Value::Object(cs) => {
-            for k in cs.keys() {
-                let id = String::from(k);
-                let mut seqs =
-                    match &cs[k] {
-                        Value::Array(toks) => toks.iter().map(
-                                    |s| String::from(s.as_str().unwrap())
-                                    ).collect(),
-                        _ => Vec::<_>::new(),
-                    };
-                seqs.sort_by(|s1,s2| s2.len().cmp(&s1.len()));
-                cat_vec.push(Category { id, seqs });
+        let (start,empty) = (e.start,e.start == e.end);
+        let es = vec![e];
+        cur = apply(&cur,&es);
+        if mode.rtl {
+            if start == 0 { break; }
+            at = start - 1;
+        } else {
+            at = start + es[0].text.len();
+            // an empty match would otherwise be found again straight away
+            if empty { at = next_char(&cur,at); }
+        }
+        batches.push(es);
+    }
+    (cur,batches)
+}
+
+fn apply(s : &str, edits : &Vec<Edit>) -> String {
+    let mut out = String::new();
+    let mut last = 0;
+    for e in edits {
+        out = out + &s[last..e.start] + &e.text;
+        last = e.end;
+    }
+    out + &s[last..]
+}
+
+// Where position p of the input ends up after the edits. Positions inside a replaced stretch move to its end.
+fn remap(p : usize, edits : &Vec<Edit>) -> usize {
+    let mut shift = 0isize;
+    for e in edits {
+        if e.start >= p {
+            break;
+        } else if e.end <= p {
+            shift += e.text.len() as isize - (e.end - e.start) as isize;
+        } else {
+            return (e.start as isize + shift) as usize + e.text.len();
+        }
+    }
+    (p as isize + shift) as usize
+}
+
+// Stress marks and the combining diacritics tone is written with
+fn is_prosodic(c : char) -> bool {
+    c == 'ˈ' || c == 'ˌ' || ('\u{300}'..='\u{36F}').contains(&c)
+}
+
+// Marks that belong to the segment before or the syllable after them, rather than being segments
+fn is_mark(c : char) -> bool {
+    c == BOUNDARY || c == SYLLABLE || is_prosodic(c)
+}
+
+// Where each syllable of a word starts, or None if the word doesn't fit the template. Onsets are as
+// long as the template allows, since each syllable is made as short as it can be. A syllable starts
+// before any stress mark on it but after any morpheme boundary.
+fn syllables(word : &str, syl : &Syllables) -> Option<Vec<usize>> {
+    let segs : Vec<(usize,char)> = word.char_indices().filter(|(_,c)| !is_mark(*c)).collect();
+    let n = segs.len();
+    let fits = |i : usize, j : usize| syl.template.is_match(&segs[i..j].iter().map(|(_,c)| c).collect::<String>());
+    // whether the rest of the word from each point on can be split into syllables
+    let mut can = vec![false; n + 1];
+    can[n] = true;
+    for i in (0..n).rev() {
+        can[i] = (i+1..=n).any(|j| can[j] && fits(i,j));
+    }
+    if n == 0 || !can[0] {
+        return None;
+    }
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i < n {
+        let mut at = segs[i].0;
+        while let Some(c) = word[..at].chars().last().filter(|c| *c == 'ˈ' || *c == 'ˌ') {
+            at -= c.len_utf8();
+        }
+        starts.push(at);
+        i = (i+1..=n).find(|&j| can[j] && fits(i,j)).unwrap();
+    }
+    Some(starts)
+}
+
+// Insert a syllable mark at each syllable break in each word of s. Morpheme boundaries are ignored
+// when parsing, and words that don't fit the template are left alone.
+fn syllabify(s : &str, syl : &Syllables) -> String {
+    let mut out = String::new();
+    for (k,word) in s.split(' ').enumerate() {
+        if k > 0 { out.push(' '); }
+        let mut last = 0;
+        for at in syllables(word,syl).unwrap_or_default().into_iter().skip(1) {
+            out.push_str(&word[last..at]);
+            out.push(SYLLABLE);
+            last = at;
+        }
+        out.push_str(&word[last..]);
+    }
+    out
+}
+
+// Iterated rules stop after this many passes, in case they never settle (`a > aa`)
+const MAX_PASSES : usize = 100;
+
+fn sca(token : &str, rule : &SCRule, mode : Mode, template : Option<&Syllables>) -> String {
+    let mut out = String::from(token);
+    for _ in 0..if mode.iterate { MAX_PASSES } else { 1 } {
+        let next = pass(&out,rule,mode,template);
+        if next == out { break; }
+        out = next;
+    }
+    out
+}
+
+fn pass(token : &str, rule : &SCRule, mode : Mode, template : Option<&Syllables>) -> String {
+    let syllabified = match (rule.syllables,template) {
+        (true,Some(t)) => syllabify(token,t),
+        _ => String::from(token),
+    };
+    let token = syllabified.as_str();
+    // run the rule as if the boundaries and prosodic marks it doesn't mention weren't there, then put them back
+    let hidden = |c : char| (c == BOUNDARY && !rule.boundaries) || (is_prosodic(c) && !rule.prosodic);
+    let mut bare = String::new();
+    let mut marks = Vec::new();
+    for c in token.chars() {
+        if hidden(c) { marks.push((bare.len(),c)); } else { bare.push(c); }
+    }
+    let (mut out,batches) = run(&bare,rule,mode);
+    for es in &batches {
+        for (p,_) in marks.iter_mut() { *p = remap(*p,es); }
+    }
+    for (p,c) in marks.iter().rev() {
+        out.insert(*p,*c);
+    }
+    out.chars().filter(|c| *c != SYLLABLE).collect()
+}
+
+pub fn rx(s : &str) -> Result<Regex,String> {
+    Regex::new(s).map_err(|e| {
+        // the regex crate's syntax errors span several lines, the last of which names the problem
+        let why = e.to_string().lines().last().unwrap_or("").trim_start_matches("error: ").to_string();
+        format!("invalid regex \"{}\" ({})",s,why)
+    })
+}
+
+// Errors are returned as (index in the list, message) so that callers can report their JSON path
+pub fn compile_multigraphs(multigraphs : &Vec<String>) -> Result<Vec<Regex>,Vec<(usize,String)>> {
+    let mut mg_rep = Vec::new();
+    let mut errs = Vec::new();
+    for (i,mg) in multigraphs.iter().enumerate() {
+        match rx(mg) {
+            Ok(r) => mg_rep.push(r),
+            Err(e) => errs.push((i,e)),
+        }
+    }
+    if errs.is_empty() { Ok(mg_rep) } else { Err(errs) }
+}
+
+// SC rules are generally of the form x->y/L_R(//NL_NR)
+// which can be read as ``x becomes y between L and R (except between NL and NR)''
+// all of these are regular expressions except y, which is just a String (`$1` etc. refer to groups in x).
+// The arrow can also be written `→` or `>`, and a single `/` before the exception is accepted too.
+// If x and y are both categories, each member of x becomes the member of y at the same position.
+// `∅` is nothing, for insertions (`∅ -> e / C_C`) and deletions (`h -> ∅ / _#`). `X²` is a geminate X,
+// so `X -> X²` geminates and `X² -> X` degeminates, and a replacement of `\\` swaps the segments matched.
+// `[+voice -son]` stands for every phoneme with those features, and as the replacement, `[-voice]`
+// swaps each matched phoneme for the one that differs from it only in voicing.
+// Separators inside brackets or parentheses, or escaped with `\`, are part of the regex, and
+// spaces around the parts are ignored (escape them if they're meant).
+// An unescaped `+` stands for a morpheme boundary; rules without one see straight through boundaries.
+// It is never the regex quantifier (write `{1,}` for that), and `--validate` warns about a `+` straight
+// after a segment or group, where it reads like one. Stress (`ˈ`, `ˌ`) and tone marks are invisible in
+// the same way to phonetic rules that don't mention them.
+// `#` marks a word edge, as in `#_` or `_#`. With a syllable template, `$` marks a syllable edge, as in `_$`
+// for open syllables; without one it's the usual end-of-string anchor.
+
+fn at(col : usize, msg : &str) -> String {
+    format!("column {}: {}",col,msg)
+}
+
+// One character of a rule, with its column (counting from 1) and whether it can be a separator
+struct Sym {
+    c:char,
+    col:usize,
+    sep:bool,
+}
+
+fn scan(rule_str : &str) -> Result<Vec<Sym>,String> {
+    let mut out = Vec::new();
+    let mut parens = Vec::new(); // columns of the open groups
+    let mut class = None;        // column of the open character class
+    let mut chars = rule_str.chars().enumerate();
+    while let Some((i,c)) = chars.next() {
+        let col = i + 1;
+        if c == '\\' {
+            match chars.next() {
+                // escaped separators lose their backslash, anything else is left for the regex
+                Some((_,d)) if "/_>→".contains(d) => out.push(Sym { c : d, col, sep : false }),
+                Some((_,d)) => {
+                    out.push(Sym { c, col, sep : false });
+                    out.push(Sym { c : d, col : col + 1, sep : false });
+                },
+                None => return Err(at(col,"nothing to escape after \"\\\"")),
             }
-        },
+            continue;
+        }
+        let sep = parens.is_empty() && class.is_none();
+        match c {
+            '[' if class.is_none() => class = Some(col),
+            ']' if class.is_some() => class = None,
+            '(' if class.is_none() => parens.push(col),
+            ')' if class.is_none() && parens.pop().is_none() => {
+                return Err(at(col,"\")\" without a \"(\" to close"));
+            },
+            _ => (),
+        }
+        out.push(Sym { c, col, sep });
+    }
+    if let Some(col) = class {
+        return Err(at(col,"unclosed \"[\""));
+    }
+    if let Some(col) = parens.last() {
+        return Err(at(*col,"unclosed \"(\""));
+    }
+    Ok(out)
+}
+
+// Columns of the unescaped `+`s straight after a segment or group, which look like quantifiers
+pub fn plus_after_atom(rule_str : &str) -> Vec<usize> {
+    let syms = scan(rule_str).unwrap_or_default();
+    syms.windows(2)
+        .filter(|w| w[1].c == '+' && w[0].c != '\\' && (w[0].c.is_alphanumeric() || ")].²".contains(w[0].c)))
+        .map(|w| w[1].col).collect()
+}
+
+fn text(syms : &[Sym]) -> String {
+    syms.iter().map(|s| s.c).collect()
+}
+
+// Column just past the end of syms, which start at `from`
+fn end_col(syms : &[Sym], from : usize) -> usize {
+    syms.last().map(|s| s.col + 1).unwrap_or(from)
+}
+
+fn split(syms : &[Sym], c : char) -> Vec<&[Sym]> {
+    syms.split(|s| s.sep && s.c == c).collect()
+}
+
+// Where the arrow between target and replacement is, and how long it is
+fn arrow(syms : &[Sym]) -> Option<(usize,usize)> {
+    (0..syms.len()).find_map(|i| match (syms[i].sep,syms[i].c,syms.get(i+1).map(|s| s.sep && s.c == '>')) {
+        (true,'-',Some(true)) => Some((i,2)),
+        (true,'→',_) | (true,'>',_) => Some((i,1)),
+        _ => None,
+    })
+}
+
+// Unescaped spaces around the parts of a rule are only for readability
+fn trim(syms : &[Sym]) -> &[Sym] {
+    let blank = |s : &Sym| s.sep && s.c.is_whitespace();
+    let start = syms.iter().position(|s| !blank(s)).unwrap_or(syms.len());
+    let end = syms.iter().rposition(|s| !blank(s)).map(|i| i + 1).unwrap_or(start);
+    &syms[start..end]
+}
+
+// Compile one regex of the rule, pointing any error at where it starts
+fn part(syms : &[Sym], from : usize, side : Side, inv : &Inventory, syllabic : bool) -> Result<Regex,String> {
+    let syms = trim(syms);
+    let col = syms.first().map(|s| s.col).unwrap_or(from);
+    if text(syms) == "∅" {
+        return rx("");
+    }
+    let expanded = natural_classes(&text(syms),inv).map_err(|(i,e)| at(syms[i].col,&e))?;
+    rx(&mark_boundaries(&expanded,side,syllabic)).map_err(|e| at(col,&e))
+}
+
+// A plain string, a category the same length as the target's (`@Stop -> @Voiced`), or a feature change like `[-voice]`
+fn parse_replacement(syms : &[Sym], target : &[Sym], cats : &Vec<Category>, inv : &Inventory) -> Result<Replacement,String> {
+    let syms = trim(syms);
+    let t = text(syms);
+    let source = text(trim(target));
+    match t.as_str() {
+        "∅" => return Ok(Replacement::Text(String::new())),
+        "\\\\" => return Ok(Replacement::Metathesis),
+        _ if !source.is_empty() && t == source.clone() + "²" => return Ok(Replacement::Geminate),
+        _ if !t.is_empty() && source == t.clone() + "²" => return Ok(Replacement::Degeminate),
         _ => (),
     }
+    if let Some(to) = category(&t,cats) {
+        let from = match category(&source,cats) {
+            Some(c) => c,
+            None => return Err(at(syms[0].col,&format!("replacing with {} needs a category as the target, not \"{}\"",t,source))),
+        };
+        if from.members.len() != to.members.len() {
+            return Err(at(syms[0].col,&format!("{} has {} members but {} has {}",from.id,from.members.len(),to.id,to.members.len())));
+        }
+        let mut pairs = HashMap::new();
+        for (x,y) in from.members.iter().zip(&to.members) {
+            pairs.entry(x.clone()).or_insert(y.clone());
+        }
+        return Ok(Replacement::Map(pairs));
+    }
+    match t.strip_prefix('[').and_then(|x| x.strip_suffix(']')).and_then(|x| inv.spec(x)) {
+        Some(Ok(spec)) => Ok(Replacement::Map(inv.changes(&spec))),
+        Some(Err(e)) => Err(at(syms[0].col,&e)),
+        None => Ok(Replacement::Text(t)),
+    }
+}
+
+fn environment(syms : &[Sym], from : usize, what : &str, inv : &Inventory, syllabic : bool) -> Result<(Regex,Regex),String> {
+    let sides = split(syms,'_');
+    match sides.len() {
+        1 => Err(at(from,&format!("{} \"{}\" needs a \"_\" for where the target goes",what,text(trim(syms))))),
+        2 => {
+            let mid = sides[0].len();
+            Ok((part(sides[0],from,Side::Left,inv,syllabic)?,part(sides[1],syms[mid].col + 1,Side::Right,inv,syllabic)?))
+        },
+        _ => Err(at(syms[sides[0].len() + 1 + sides[1].len()].col,&format!("{} \"{}\" has more than one \"_\"",what,text(trim(syms))))),
+    }
+}
+
+// `syllabic` is whether there's a syllable template, which decides what `$` means
+fn parse_rule(rule_str : &str, cats : &Vec<Category>, inv : &Inventory, syllabic : bool) -> Result<SCRule,String> {
+    let syms = scan(rule_str)?;
+    let segs = split(&syms,'/');
+    let change = segs[0];
+    let (i,len) = match arrow(change) {
+        Some(a) => a,
+        None => return Err(at(end_col(change,1),"expected \"->\", \"→\" or \">\" after the target")),
+    };
+    let (target,replacement) = (&change[..i],&change[i+len..]);
+    if let Some((j,_)) = arrow(replacement) {
+        return Err(at(replacement[j].col,"more than one arrow"));
+    }
+    let env_col = end_col(change,1) + 1;
+    let pos = match segs.get(1) {
+        Some(env) => environment(env,env_col,"environment",inv,syllabic)?,
+        None => return Err(at(env_col - 1,"missing environment (\"/ _\" for anywhere)")),
+    };
+    // `//` introduces the exception; a lone `/` does too
+    let slashes : Vec<usize> = syms.iter().filter(|s| s.sep && s.c == '/').map(|s| s.col).collect();
+    let exc = match &segs[2..] {
+        [] => None,
+        [e] => Some(*e),
+        [[],e] => Some(*e),
+        [[],..] => return Err(at(slashes[3],"too many \"/\"")),
+        _ => return Err(at(slashes[2],"too many \"/\"")),
+    };
+    let neg_env = match exc {
+        Some(e) if trim(e).is_empty() => return Err(at(*slashes.last().unwrap(),"empty exception after \"/\"")),
+        Some(e) => {
+            let col = e[0].col;
+            Some(environment(e,col,"exception",inv,syllabic)?)
+        },
+        None => None,
+    };
+    let target_syms = target;
+    let target = part(target,1,Side::Target,inv,syllabic)?;
+    let regexes : Vec<&Regex> = [&target,&pos.0,&pos.1].into_iter().chain(neg_env.iter().flat_map(|(l,r)| [l,r])).collect();
+    let boundaries = regexes.iter().any(|r| r.as_str().contains(BOUNDARY));
+    let prosodic = regexes.iter().any(|r| r.as_str().chars().any(is_prosodic));
+    let syllables = regexes.iter().any(|r| r.as_str().contains(SYLLABLE));
+    Ok(SCRule {
+        source: String::from(rule_str),
+        target,
+        replacement: parse_replacement(replacement,target_syms,cats,inv)?,
+        pos_env: pos,
+        neg_env,
+        boundaries,
+        prosodic,
+        syllables,
+    })
+}
+
+// Category ids are `@` and a name of letters and digits, so `@C_` is `@C` before the target, `@C²` its geminate
+// and `@Cˈ` a C before a stressed syllable
+pub fn is_name_char(c : char) -> bool {
+    (c.is_alphabetic() || c.is_ascii_digit()) && !is_prosodic(c)
+}
+
+// Each `@name` in s, as (byte index, the whole `@name`)
+fn cat_refs(s : &str) -> Vec<(usize,&str)> {
+    s.match_indices('@').map(|(i,_)| {
+        let len = s[i+1..].find(|c : char| !is_name_char(c)).unwrap_or(s.len() - i - 1);
+        (i,&s[i..i+1+len])
+    }).collect()
+}
+
+// Every `@name` in a rule must be the id of some category
+fn check_cats(rule_str : &str, cats : &Vec<Category>) -> Result<(),String> {
+    for (i,name) in cat_refs(rule_str) {
+        if !cats.iter().any(|c| c.id == name) {
+            return Err(at(rule_str[..i].chars().count() + 1,&format!("undefined category \"{}\"",name)));
+        }
+    }
+    Ok(())
+}
+
+fn categories(cats : &BTreeMap<String,Vec<String>>) -> Vec<Category> {
+    let mut cat_vec : Vec<Category> = Vec::new();
+    for (k,toks) in cats {
+        let id = String::from(k);
+        let mut seqs = toks.clone();
+        seqs.sort_by(|s1,s2| s2.len().cmp(&s1.len()));
+        cat_vec.push(Category { id, seqs, members : toks.clone() });
+    }
+    cat_vec
+}
+
+// A syllable template like `(C)(C)V(C)` lists categories, each a single capital letter or an `@` name,
+// with the optional ones in parentheses. It's compiled to a regex matching exactly one syllable.
+// The first category that isn't optional is taken to be the nucleus.
+pub fn compile_template(template : &str, cats : &BTreeMap<String,Vec<String>>, multigraphs : &Vec<Regex>) -> Result<Syllables,String> {
+    let cat_vec = categories(cats);
+    let chars : Vec<char> = template.chars().collect();
+    let mut out = String::new();
+    let mut nucleus = None;
+    let mut optional = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let name = match c {
+            '(' if !optional => { optional = true; out.push('('); i += 1; continue; },
+            ')' if optional => { optional = false; out.push_str(")?"); i += 1; continue; },
+            c if c.is_whitespace() => { i += 1; continue; },
+            '@' => {
+                let len = chars[i+1..].iter().take_while(|c| is_name_char(**c)).count();
+                let name : String = chars[i..i+1+len].iter().collect();
+                i += 1 + len;
+                name
+            },
+            c if c.is_uppercase() => { i += 1; format!("@{}",c) },
+            c => return Err(at(i + 1,&format!("unexpected \"{}\" in syllable template",c))),
+        };
+        match cat_vec.iter().find(|cat| cat.id == name) {
+            Some(cat) => {
+                let alt = String::from("(?:") + &cat.seqs.join("|") + ")";
+                if !optional && nucleus.is_none() {
+                    nucleus = Some(rx(&format!("^{}",multigraph_to_unigraph(&alt,multigraphs)))?);
+                }
+                out = out + &alt;
+            },
+            None => return Err(at(i,&format!("undefined category \"{}\"",name))),
+        }
+    }
+    if optional {
+        return Err(at(chars.len(),"unclosed \"(\" in syllable template"));
+    }
+    let template = rx(&format!("^(?:{})$",multigraph_to_unigraph(&out,multigraphs)))?;
+    Ok(Syllables { template, nucleus })
+}
+
+// Compile an `sc`-style rule list against the language's categories, phonemes, (already compiled) multigraphs
+// and syllable template
+fn mode(entry : &RuleEntry) -> Result<Mode,String> {
+    let b = match entry {
+        RuleEntry::Rule(_) => return Ok(Mode::default()),
+        RuleEntry::Block(b) => b,
+    };
+    let sequential = match b.apply.as_deref() {
+        None | Some("simultaneous") => false,
+        Some("sequential") => true,
+        Some(a) => return Err(format!("\"apply\" should be \"simultaneous\" or \"sequential\", not \"{}\"",a)),
+    };
+    let rtl = match b.direction.as_deref() {
+        None | Some("ltr") => false,
+        Some("rtl") => true,
+        Some(d) => return Err(format!("\"direction\" should be \"ltr\" or \"rtl\", not \"{}\"",d)),
+    };
+    Ok(Mode { sequential, rtl, iterate : b.iterate.unwrap_or(false) })
+}
+
+// Errors are returned as (entry index, index in the block's rules if it's a block, message)
+pub fn compile_rules(sc : &Vec<RuleEntry>, cats : &BTreeMap<String,Vec<String>>, phonemes : &BTreeMap<String,BTreeMap<String,String>>, multigraphs : &Vec<Regex>, syllable : Option<Syllables>) -> Result<SoundChanges,Vec<(usize,Option<usize>,String)>> {
+    let inv = Inventory::new(phonemes);
+    let cat_vec = categories(cats);
+    let mut blocks = Vec::new();
+    let mut names = BTreeSet::new();
+    let mut errs = Vec::new();
+    for (i,entry) in sc.iter().enumerate() {
+        let (name,rules) = match entry {
+            RuleEntry::Rule(r) => (None,vec![(None,r)]),
+            RuleEntry::Block(b) => (b.name.clone(),b.rules.iter().enumerate().map(|(j,r)| (Some(j),r)).collect()),
+        };
+        if let Some(n) = &name {
+            if !names.insert(n.clone()) {
+                errs.push((i,None,format!("there is already a stage named \"{}\"",n)));
+            }
+        }
+        let mode = mode(entry).unwrap_or_else(|e| { errs.push((i,None,e)); Mode::default() });
+        let mut compiled = Vec::new();
+        for (j,rule_str) in rules {
+            match check_cats(rule_str,&cat_vec).and_then(|_| parse_rule(rule_str,&cat_vec,&inv,syllable.is_some())).and_then(|r| from_cats(r,&cat_vec,multigraphs)).and_then(anchor) {
+                Ok(r) => compiled.push(r),
+                Err(e) => errs.push((i,j,e)),
+            }
+        }
+        blocks.push(Block { name, rules : compiled, mode });
+    }
+    if errs.is_empty() {
+        Ok(SoundChanges { blocks, multigraphs: multigraphs.clone(), syllable })
+    } else {
+        Err(errs)
+    }
+}
+
+// A rule that changed a word, and the word before and after it, with its boundaries shown as `-`
+#[derive(Serialize,Clone)]
+pub struct Step {
+    pub rule : String,
+    pub stage : Option<String>,
+    pub before : String,
+    pub after : String,
+    #[serde(skip)]
+    pub surface : String, // `after` without the boundaries, for `derivation`
+}
+
+// The token is a morpheme line from `lex::sc_input`; its boundaries are kept in the output so that
+// later rules can still see them (see `surface`). If `stage` names a block, the rules after it aren't run.
+// The rules that changed the token are returned with it, in order.
+pub fn to_orthography(token : String, sc : &SoundChanges, stage : Option<&str>) -> (String,Vec<Step>) {
+    let show = |s : &str| unigraph_to_multigraph(s,&sc.multigraphs).replace(BOUNDARY,"-");
     // Run forward multigraph replacements 
-    let mut s0 = multigraph_to_unigraph(token.as_str(),&mg_rep);
-
-    // SC rules are generally of the form x->y/L_R(/NL_NR)
-    // which can be read as ``x becomes y between L and R (except between NL and NR)''
-    // all of these are regular expressions except y, which is just a String
-    for rule_str in rules {
-        let mut rule_coll = rule_str.unwrap().split("/");
-        let change : Vec<&str> = rule_coll.next().unwrap().split("->").collect();
-        let pos : Vec<&str> = rule_coll.next().unwrap().split("_").collect();
-        let neg : Vec<&str> =
-            match rule_coll.next() {
-                Some(env) => env.split("_").collect(),
-                None => vec!["",""],
+    let mut s0 = multigraph_to_unigraph(&token,&sc.multigraphs);
+    let mut steps = Vec::new();
+    for block in &sc.blocks {
+        for rule in &block.rules {
+            let s1 = sca(&s0,rule,block.mode,sc.syllable.as_ref());
+            if s1 != s0 {
+                let surface = surface(&unigraph_to_multigraph(&s1,&sc.multigraphs));
+                steps.push(Step { rule : rule.to_string(), stage : block.name.clone(), before : show(&s0), after : show(&s1), surface });
+                s0 = s1;
+            }
+        }
+        if stage.is_some() && block.name.as_deref() == stage {
+            break;
+        }
+    }
+    (unigraph_to_multigraph(&s0,&sc.multigraphs),steps)
+}
+
+// A word's derivation from its morpheme line, written like `*kata > kada > kad`
+pub fn derivation(input : &str, steps : &[Step]) -> String {
+    let input = surface(input);
+    std::iter::once(format!("*{}",input)).chain(steps.iter().map(|s| s.surface.clone())).collect::<Vec<_>>().join(" > ")
+}
+
+pub enum Position {
+    Initial,
+    Final,
+    Penultimate,
+    Antepenultimate,
+    Syllable(i64),
+}
+
+pub fn stress_position(e : &StressEntry) -> Result<Position,String> {
+    match e {
+        StressEntry::Named(n) => match n.as_str() {
+            "initial" => Ok(Position::Initial),
+            "final" => Ok(Position::Final),
+            "penultimate" => Ok(Position::Penultimate),
+            "antepenultimate" => Ok(Position::Antepenultimate),
+            _ => Err(format!("expected \"initial\", \"final\", \"penultimate\", \"antepenultimate\" or a syllable number, not \"{}\"",n)),
+        },
+        StressEntry::Syllable(0) => Err(String::from("syllables are numbered from 1 (or from -1 at the end)")),
+        StressEntry::Syllable(i) => Ok(Position::Syllable(*i)),
+    }
+}
+
+pub enum Spread {
+    Right,
+    Left,
+    Off,
+}
+
+// Stress and tone, assigned to the orthographic form so the phonetic rules can refer to them
+pub struct Prosody {
+    stress:Option<(Position,Option<Regex>)>, // default position, and what a heavy syllable ends with
+    tone:Option<(BTreeMap<String,String>,Spread)>,
+}
+
+pub fn compile_prosody(stress : &Option<StressConfig>, tone : &Option<ToneConfig>, cats : &BTreeMap<String,Vec<String>>, multigraphs : &Vec<Regex>) -> Result<Prosody,String> {
+    let cat_vec = categories(cats);
+    let stress = match stress {
+        Some(cfg) => {
+            let heavy = match &cfg.heavy {
+                Some(h) => Some(rx(&format!("(?:{})$",multigraph_to_unigraph(&expand_cats(h,&cat_vec),multigraphs)))?),
+                None => None,
+            };
+            // a bad position is reported by `langfile::validate`
+            stress_position(&cfg.position).ok().map(|p| (p,heavy))
+        },
+        None => None,
+    };
+    let tone = tone.as_ref().map(|cfg| {
+        let spread = match cfg.spread.as_deref() {
+            Some("left") => Spread::Left,
+            Some("none") => Spread::Off,
+            _ => Spread::Right,
+        };
+        (cfg.marks.clone(),spread)
+    });
+    Ok(Prosody { stress, tone })
+}
+
+// Which of a word's syllables is stressed. A light syllable where stress would fall passes it on to
+// its neighbour away from the edge, so "penultimate" with heavy syllables is the Latin rule.
+fn stressed(spans : &Vec<(usize,usize)>, s : &str, default : &Position, heavy : &Option<Regex>, lexical : Option<&Position>) -> usize {
+    let n = spans.len() as i64;
+    let k = match lexical.unwrap_or(default) {
+        Position::Initial => 0,
+        Position::Final => n - 1,
+        Position::Penultimate => n - 2,
+        Position::Antepenultimate => n - 3,
+        Position::Syllable(i) if *i > 0 => i - 1,
+        Position::Syllable(i) => n + i,
+    }.clamp(0,n - 1);
+    let light = |k : i64| {
+        let (a,b) = spans[k as usize];
+        let bare : String = s[a..b].chars().filter(|c| !is_mark(*c)).collect();
+        heavy.as_ref().map(|h| !h.is_match(&bare)).unwrap_or(false)
+    };
+    if lexical.is_some() || !light(k) {
+        return k as usize;
+    }
+    match default {
+        Position::Initial => (k + 1).min(n - 1) as usize,
+        _ => (k - 1).max(0) as usize,
+    }
+}
+
+// Link tones to syllables one to one, starting from the edge opposite the spreading direction.
+// Extra tones crowd onto the last syllable as a contour, and the last tone spreads over any syllables left.
+fn associate<'a>(melody : &'a Vec<String>, n : usize, spread : &Spread) -> Vec<Vec<&'a String>> {
+    let mut out = vec![Vec::new(); n];
+    if n == 0 || melody.is_empty() {
+        return out;
+    }
+    let flip = matches!(spread,Spread::Left);
+    let idx = |i : usize| if flip { n - 1 - i } else { i };
+    let tones : Vec<&String> = if flip { melody.iter().rev().collect() } else { melody.iter().collect() };
+    for (i,t) in tones.iter().enumerate() {
+        out[idx(i.min(n - 1))].push(*t);
+    }
+    if !matches!(spread,Spread::Off) {
+        for i in tones.len()..n {
+            out[idx(i)] = vec![*tones.last().unwrap()];
+        }
+    }
+    if flip {
+        for ts in out.iter_mut() { ts.reverse(); }
+    }
+    out
+}
+
+// Where the nucleus of a syllable ends, to put a tone mark there
+fn nucleus_end(syl : &str, syllables : &Syllables) -> Option<usize> {
+    let nucleus = syllables.nucleus.as_ref()?;
+    syl.char_indices().filter(|(_,c)| !is_mark(*c)).find_map(|(i,_)| nucleus.find(&syl[i..]).map(|m| i + m.end()))
+}
+
+// Mark stress (`ˈ` before the syllable) and tone (diacritics after the nucleus) on a form from
+// `to_orthography`, syllabified with the same template the rules use. Lexical stress overrides the
+// language's, and the melody is the word's tones in order (see `lex::melody`).
+pub fn prosody(token : &str, p : &Prosody, sc : &SoundChanges, lexical : Option<&StressEntry>, melody : &Vec<String>) -> String {
+    let syl = match &sc.syllable {
+        Some(syl) => syl,
+        None => return String::from(token),
+    };
+    let lexical = lexical.and_then(|e| stress_position(e).ok());
+    let s = multigraph_to_unigraph(token,&sc.multigraphs);
+    let mut inserts : Vec<(usize,String)> = Vec::new();
+    let mut all = Vec::new();
+    let mut base = 0;
+    for word in s.split(' ') {
+        if let Some(starts) = syllables(word,syl) {
+            let spans : Vec<(usize,usize)> = starts.iter().enumerate()
+                .map(|(i,a)| (base + a,base + starts.get(i + 1).copied().unwrap_or(word.len())))
+                .collect();
+            if let Some((default,heavy)) = &p.stress {
+                let k = stressed(&spans,&s,default,heavy,lexical.as_ref());
+                inserts.push((spans[k].0,String::from("ˈ")));
+            }
+            all.extend(spans);
+        }
+        base += word.len() + 1;
+    }
+    if let Some((marks,spread)) = &p.tone {
+        for (i,tones) in associate(melody,all.len(),spread).iter().enumerate().filter(|(_,ts)| !ts.is_empty()) {
+            let (a,b) = all[i];
+            let at = nucleus_end(&s[a..b],syl).map(|e| a + e).unwrap_or(b);
+            let key : String = tones.iter().map(|t| t.as_str()).collect();
+            let mark = marks.get(&key).cloned()
+                .unwrap_or(tones.iter().filter_map(|t| marks.get(*t)).map(|m| m.as_str()).collect());
+            inserts.push((at,mark));
+        }
+    }
+    let mut out = s.clone();
+    inserts.sort_by_key(|(at,_)| *at);
+    for (at,mark) in inserts.iter().rev() {
+        out.insert_str(*at,mark);
+    }
+    unigraph_to_multigraph(&out,&sc.multigraphs)
+}
+
+enum Segment {
+    Trigger(usize), // sets the class, by index into the system's classes
+    Transparent,
+    Archiphoneme(Vec<String>), // its realisation in each class
+}
+
+// A harmony system, compiled by `compile_harmony`
+pub struct Harmony {
+    segments:Vec<(String,Segment)>, // longest first, so that "aa" is found before "a"
+    default:usize,
+    morpheme:bool, // whether each morpheme is its own domain
+    rtl:bool,
+}
+
+fn compile_system(h : &HarmonyEntry) -> Result<Harmony,String> {
+    let classes : Vec<&String> = h.classes.keys().collect();
+    let class = |c : &String, what : &str| classes.iter().position(|k| *k == c)
+        .ok_or(format!("{} \"{}\" isn't one of the classes ({})",what,c,classes.iter().map(|k| k.as_str()).collect::<Vec<_>>().join(", ")));
+    let default = class(&h.default,"default class")?;
+    let mut segments = Vec::new();
+    for (i,members) in h.classes.values().enumerate() {
+        for m in members.iter().filter(|m| !h.transparent.contains(m)) {
+            segments.push((m.clone(),Segment::Trigger(i)));
+        }
+    }
+    for (seg,c) in &h.opaque {
+        segments.push((seg.clone(),Segment::Trigger(class(c,&format!("class of opaque \"{}\"",seg))?)));
+    }
+    for t in &h.transparent {
+        segments.push((t.clone(),Segment::Transparent));
+    }
+    for (a,forms) in &h.archiphonemes {
+        let forms = classes.iter().map(|c| forms.get(*c).cloned().ok_or(format!("archiphoneme \"{}\" has no form for class \"{}\"",a,c)))
+            .collect::<Result<Vec<_>,_>>()?;
+        segments.push((a.clone(),Segment::Archiphoneme(forms)));
+    }
+    let mut seen = BTreeSet::new();
+    for (seg,_) in &segments {
+        if seg.is_empty() {
+            return Err(String::from("segments can't be empty"));
+        } else if !seen.insert(seg) {
+            return Err(format!("\"{}\" is declared more than once",seg));
+        }
+    }
+    segments.sort_by_key(|(seg,_)| std::cmp::Reverse(seg.chars().count()));
+    let morpheme = match h.domain.as_deref() {
+        None | Some("word") => false,
+        Some("morpheme") => true,
+        Some(d) => return Err(format!("\"domain\" should be \"word\" or \"morpheme\", not \"{}\"",d)),
+    };
+    let rtl = match h.direction.as_deref() {
+        None | Some("ltr") => false,
+        Some("rtl") => true,
+        Some(d) => return Err(format!("\"direction\" should be \"ltr\" or \"rtl\", not \"{}\"",d)),
+    };
+    Ok(Harmony { segments, default, morpheme, rtl })
+}
+
+// Errors are returned as (index in the list, message)
+pub fn compile_harmony(systems : &Vec<HarmonyEntry>) -> Result<Vec<Harmony>,Vec<(usize,String)>> {
+    let mut out = Vec::new();
+    let mut errs = Vec::new();
+    for (i,h) in systems.iter().enumerate() {
+        match compile_system(h) {
+            Ok(h) => out.push(h),
+            Err(e) => errs.push((i,e)),
+        }
+    }
+    if errs.is_empty() { Ok(out) } else { Err(errs) }
+}
+
+// Realise the archiphonemes in a morpheme line (`-lAr` -> `-ler` after `ev`). Each one takes the class of
+// the last trigger before it in its domain (after it, right to left); nothing in the lines
+// other than declared segments and morpheme boundaries matters to harmony.
+pub fn harmonize(token : String, systems : &Vec<Harmony>) -> String {
+    let mut token = token;
+    for h in systems {
+        // split into declared segments and everything else
+        let mut units : Vec<(&str,Option<&Segment>)> = Vec::new();
+        let mut i = 0;
+        while i < token.len() {
+            let rest = &token[i..];
+            let (len,seg) = match h.segments.iter().find(|(s,_)| rest.starts_with(s.as_str())) {
+                Some((s,seg)) => (s.len(),Some(seg)),
+                None => (rest.chars().next().unwrap().len_utf8(),None),
             };
-        assert_eq!(neg.len(),2);
-        let rule0 = SCRule {
-            target: Regex::new(change[0]).unwrap(),
-            replacement: String::from(change[1]),
-            pos_env: (Regex::new(pos[0]).unwrap(),Regex::new(pos[1]).unwrap()),
-            neg_env: (Regex::new(neg[0]).unwrap(),Regex::new(neg[1]).unwrap()),
+            units.push((&rest[..len],seg));
+            i += len;
+        }
+        if h.rtl { units.reverse(); }
+        let mut class = None;
+        let mut out : Vec<&str> = Vec::new();
+        for (text,seg) in units {
+            match seg {
+                Some(Segment::Trigger(c)) => class = Some(*c),
+                Some(Segment::Archiphoneme(forms)) => {
+                    out.push(&forms[class.unwrap_or(h.default)]);
+                    continue;
+                },
+                Some(Segment::Transparent) => (),
+                None if text == " " || (h.morpheme && text == BOUNDARY.to_string()) => class = None,
+                None => (),
+            }
+            out.push(text);
+        }
+        if h.rtl { out.reverse(); }
+        token = out.concat();
+    }
+    token
+}
+
+// Show the syllable breaks in a form from `to_orthography` with `.`
+pub fn show_syllables(token : &str, sc : &SoundChanges) -> String {
+    match &sc.syllable {
+        Some(t) => {
+            let marked = multigraph_to_unigraph(token,&sc.multigraphs);
+            // the stress mark doubles as a syllable break
+            let s = syllabify(&marked,t).replace(SYLLABLE,".").replace(".ˈ","ˈ").replace(".ˌ","ˌ");
+            unigraph_to_multigraph(&s,&sc.multigraphs)
+        },
+        None => String::from(token),
+    }
+}
+
+// The form as written, without morpheme boundaries
+pub fn surface(token : &str) -> String {
+    token.replace(BOUNDARY,"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cats() -> BTreeMap<String,Vec<String>> {
+        [("@V",vec!["a","e","i","o","u"]),("@C",vec!["p","t","k","d","n"]),("@Voiced",vec!["b","d","g"])].iter()
+            .map(|(k,v)| (k.to_string(),v.iter().map(|s| s.to_string()).collect())).collect()
+    }
+
+    fn compile(rules : &[&str]) -> Result<SoundChanges,Vec<(usize,Option<usize>,String)>> {
+        let sc = rules.iter().map(|r| RuleEntry::Rule(r.to_string())).collect();
+        compile_rules(&sc,&cats(),&BTreeMap::new(),&Vec::new(),None)
+    }
+
+    fn run_rules(rules : &[&str], word : &str) -> String {
+        match compile(rules) {
+            Ok(sc) => to_orthography(String::from(word),&sc,None).0,
+            Err(e) => panic!("{:?}",e),
+        }
+    }
+
+    #[test]
+    fn dollar_is_end_anchor_without_template() {
+        assert_eq!(run_rules(&["e -> ∅ / _$"],"kate"),"kat");
+        assert_eq!(run_rules(&["e -> ∅ / _$"],"keta"),"keta");
+    }
+
+    #[test]
+    fn dollar_is_syllable_edge_with_template() {
+        let cats = cats();
+        let template = compile_template("(C)V(C)",&cats,&Vec::new()).ok();
+        let sc = vec![RuleEntry::Rule(String::from("a -> o / _$"))];
+        match compile_rules(&sc,&cats,&BTreeMap::new(),&Vec::new(),template) {
+            Ok(sc) => assert_eq!(to_orthography(String::from("kata"),&sc,None).0,"koto"),
+            Err(e) => panic!("{:?}",e),
+        }
+    }
+
+    #[test]
+    fn undefined_category_with_a_defined_prefix() {
+        match compile(&["@Vowl -> e / _"]) {
+            Ok(_) => panic!("@Vowl was accepted"),
+            Err(e) => assert_eq!(e[0].2,"column 1: undefined category \"@Vowl\""),
+        }
+        assert_eq!(run_rules(&["@C² -> @C / _"],"katta"),"kata");
+    }
+
+    #[test]
+    fn category_before_the_target() {
+        assert_eq!(run_rules(&["a -> o / @C_"],"ka"),"ko");
+    }
+
+    #[test]
+    fn categories_with_overlapping_ids() {
+        assert_eq!(run_rules(&["a -> o / @Voiced_"],"da"),"do");
+        assert_eq!(run_rules(&["a -> o / @Voiced_"],"ta"),"ta");
+        assert_eq!(run_rules(&["@Voiced -> @Voiced² / @V_"],"ada"),"adda");
+    }
+
+    #[test]
+    fn plus_after_a_segment_looks_like_a_quantifier() {
+        assert_eq!(plus_after_atom("a+ -> o / _"),vec![2]);
+        assert_eq!(plus_after_atom("(ab)+ -> o / _"),vec![5]);
+        assert_eq!(plus_after_atom("a -> o / _+k"),Vec::<usize>::new());
+        assert_eq!(plus_after_atom("a\\+ -> o / _"),Vec::<usize>::new());
+        // warnings point at the rule in the file, even after an entry that didn't parse
+        let json = serde_json::json!({"sc": [{"rules": 1}, "a -> o / _", {"rules": ["a -> o / _", "a+ -> o / _"]}]});
+        let (file,_) = crate::langfile::parse(&json);
+        let paths : Vec<String> = crate::langfile::lint(&json,&file).iter().map(|p| p.to_string()).collect();
+        assert_eq!(paths.len(),1);
+        assert!(paths[0].starts_with("sc[2].rules[1]"),"{}",paths[0]);
+    }
+
+    #[test]
+    fn hyphens_in_forms_are_not_boundaries() {
+        let word = format!("ka-t{}ta",BOUNDARY);
+        assert_eq!(surface(&run_rules(&["t -> d / _"],&word)),"ka-dda");
+        assert_eq!(surface(&run_rules(&["a -> a-o / _#"],"kata")),"kata-o");
+        assert_eq!(run_rules(&["t -> d / _+"],&word),format!("ka-d{}ta",BOUNDARY));
+    }
+
+    fn err(rule : &str) -> String {
+        match compile(&[rule]) {
+            Ok(_) => panic!("{} was accepted",rule),
+            Err(e) => e[0].2.clone(),
+        }
+    }
+
+    #[test]
+    fn rule_errors() {
+        let cases = [
+            ("a -> b / _\\","column 11: nothing to escape after \"\\\""),
+            ("(a -> b / _","column 1: unclosed \"(\""),
+            ("a) -> b / _","column 2: \")\" without a \"(\" to close"),
+            ("[ab -> b / _","column 1: unclosed \"[\""),
+            ("a b / _","column 5: expected \"->\", \"→\" or \">\" after the target"),
+            ("a -> b -> c / _","column 8: more than one arrow"),
+            ("a -> b","column 7: missing environment (\"/ _\" for anywhere)"),
+            ("a -> b / _ / c_ / d","column 17: too many \"/\""),
+            ("a -> b / _ // c_ // d","column 18: too many \"/\""),
+            ("a -> b / _/","column 11: empty exception after \"/\""),
+            ("a -> b / _//","column 12: empty exception after \"/\""),
+            ("a -> b / c","column 9: environment \"c\" needs a \"_\" for where the target goes"),
+            ("a -> b / _c_","column 12: environment \"_c_\" has more than one \"_\""),
+            ("a -> b / _ // c","column 14: exception \"c\" needs a \"_\" for where the target goes"),
+            ("a -> @V / _","column 6: replacing with @V needs a category as the target, not \"a\""),
+            ("@Voiced -> @V / _","column 12: @Voiced has 3 members but @V has 5"),
+            ("a -> b / _*","column 11: invalid regex \"*\" (repetition operator missing expression)"),
+            ("@Vowl -> b / _","column 1: undefined category \"@Vowl\""),
+        ];
+        for (rule,msg) in cases {
+            assert_eq!(err(rule),msg,"{}",rule);
+        }
+    }
+
+    #[test]
+    fn insertion_and_deletion() {
+        assert_eq!(run_rules(&["∅ -> e / t_k"],"atka"),"ateka");
+        assert_eq!(run_rules(&["∅ -> i / _#"],"kat"),"kati");
+        assert_eq!(run_rules(&["∅ -> e / t_"],"tt"),"tete");
+        assert_eq!(run_rules(&["h -> ∅ / _#"],"kah"),"ka");
+        assert_eq!(run_rules(&["e -> ∅ / @C_@C"],"katena"),"katna");
+        assert_eq!(run_rules(&["e -> ∅ / @C_@C"],"kate"),"kate");
+    }
+
+    #[test]
+    fn metathesis() {
+        assert_eq!(run_rules(&["sk -> \\\\ / _"],"aska"),"aksa");
+        assert_eq!(run_rules(&["sk -> \\\\ / #_"],"aska"),"aska");
+        assert_eq!(run_rules(&["@V@C -> \\\\ / #_"],"apka"),"paka");
+    }
+
+    #[test]
+    fn gemination() {
+        assert_eq!(run_rules(&["t -> t² / a_a"],"ata"),"atta");
+        assert_eq!(run_rules(&["@C -> @C² / @V_@V"],"apa"),"appa");
+        assert_eq!(run_rules(&["t² -> t / _"],"katta"),"kata");
+        assert_eq!(run_rules(&["@C² -> @C / _#"],"kattakk"),"kattak");
+    }
+
+    fn run_block(rule : &str, apply : &str, direction : &str, iterate : bool, word : &str) -> String {
+        let block = crate::langfile::BlockEntry {
+            name : None,
+            rules : vec![String::from(rule)],
+            apply : Some(String::from(apply)),
+            direction : Some(String::from(direction)),
+            iterate : Some(iterate),
+        };
+        match compile_rules(&vec![RuleEntry::Block(block)],&cats(),&BTreeMap::new(),&Vec::new(),None) {
+            Ok(sc) => to_orthography(String::from(word),&sc,None).0,
+            Err(e) => panic!("{:?}",e),
+        }
+    }
+
+    #[test]
+    fn application_modes() {
+        assert_eq!(run_block("a -> b / a_","simultaneous","ltr",false,"aaaa"),"abbb");
+        assert_eq!(run_block("a -> b / a_","sequential","ltr",false,"aaaa"),"abab");
+        assert_eq!(run_block("a -> b / a_","sequential","rtl",false,"aaaa"),"abbb");
+        assert_eq!(run_block("a -> b / _a","sequential","rtl",false,"aaaa"),"baba");
+        // overlapping matches: left to right takes the first, right to left the last
+        assert_eq!(run_block("aa -> b / _","simultaneous","ltr",false,"aaa"),"ba");
+        assert_eq!(run_block("aa -> b / _","simultaneous","rtl",false,"aaa"),"ab");
+    }
+
+    #[test]
+    fn iteration() {
+        assert_eq!(run_block("a -> b / _b","simultaneous","ltr",false,"aaab"),"aabb");
+        assert_eq!(run_block("a -> b / _b","simultaneous","ltr",true,"aaab"),"bbbb");
+        // a rule that never settles stops after MAX_PASSES
+        assert_eq!(run_block("a -> ab / #_","simultaneous","ltr",true,"a").len(),MAX_PASSES + 1);
+    }
+
+    fn with_features(rule : &str) -> Result<SoundChanges,Vec<(usize,Option<usize>,String)>> {
+        let phonemes = [("p","-","-","-"),("b","+","-","-"),("t","-","-","+"),("d","+","-","+"),("n","+","+","+"),("a","+","+","-")].iter()
+            .map(|(p,voice,son,cor)| (p.to_string(),[("voice",voice),("son",son),("cor",cor)].iter().map(|(f,v)| (f.to_string(),v.to_string())).collect()))
+            .collect();
+        compile_rules(&vec![RuleEntry::Rule(String::from(rule))],&cats(),&phonemes,&Vec::new(),None)
+    }
+
+    #[test]
+    fn natural_classes_and_feature_changes() {
+        match with_features("[+voice -son] -> [-voice] / _#") {
+            Ok(sc) => assert_eq!(to_orthography(String::from("tabad"),&sc,None).0,"tabat"),
+            Err(e) => panic!("{:?}",e),
+        }
+        // a longer target has each of its segments changed
+        match with_features("[+voice -son]a -> [-voice] / _") {
+            Ok(sc) => assert_eq!(to_orthography(String::from("bada"),&sc,None).0,"pata"),
+            Err(e) => panic!("{:?}",e),
+        }
+    }
+
+    #[test]
+    fn unknown_features() {
+        for rule in ["[+vocie -son] -> ∅ / _","[+vocie] -> ∅ / _","a -> [-vocie] / _","a -> b / [-ab]_"] {
+            match with_features(rule) {
+                Ok(_) => panic!("{} was accepted",rule),
+                Err(e) => assert!(e[0].2.contains("unknown phonological feature"),"{}: {}",rule,e[0].2),
+            }
+        }
+        // without a `phonemes` section, brackets are only regex classes
+        assert_eq!(run_rules(&["a -> o / [-b]_"],"kbab"),"kbob");
+    }
+
+    fn vowel_harmony(change : impl FnOnce(&mut HarmonyEntry)) -> Vec<Harmony> {
+        let strings = |v : &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        let forms = |back : &str, front : &str| [("back",back),("front",front)].iter().map(|(k,v)| (k.to_string(),v.to_string())).collect();
+        let mut h = HarmonyEntry {
+            classes : [("back",strings(&["a","ı","o","u"])),("front",strings(&["e","i","ö","ü"]))].into_iter().map(|(k,v)| (k.to_string(),v)).collect(),
+            archiphonemes : [("A",forms("a","e")),("I",forms("ı","i"))].into_iter().map(|(k,v)| (k.to_string(),v)).collect(),
+            default : String::from("back"),
+            transparent : Vec::new(),
+            opaque : BTreeMap::new(),
+            domain : None,
+            direction : None,
+        };
+        change(&mut h);
+        match compile_harmony(&vec![h]) {
+            Ok(h) => h,
+            Err(e) => panic!("{:?}",e),
+        }
+    }
+
+    fn harmonized(morphs : &[&str], systems : &Vec<Harmony>) -> String {
+        surface(&harmonize(morphs.join(&BOUNDARY.to_string()),systems))
+    }
+
+    #[test]
+    fn suffixes_agree_with_the_stem() {
+        let h = vowel_harmony(|_| ());
+        assert_eq!(harmonized(&["ev","lAr"],&h),"evler");
+        assert_eq!(harmonized(&["at","lAr"],&h),"atlar");
+        assert_eq!(harmonized(&["ev","lAr","In"],&h),"evlerin");
+        // nothing to agree with
+        assert_eq!(harmonized(&["lAr"],&h),"lar");
+    }
+
+    #[test]
+    fn transparent_and_opaque_segments() {
+        let h = vowel_harmony(|_| ());
+        assert_eq!(harmonized(&["kali","lAr"],&h),"kaliler");
+        let h = vowel_harmony(|h| h.transparent = vec![String::from("i")]);
+        assert_eq!(harmonized(&["kali","lAr"],&h),"kalilar");
+        let h = vowel_harmony(|h| { h.opaque.insert(String::from("y"),String::from("front")); });
+        assert_eq!(harmonized(&["kay","lAr"],&h),"kayler");
+    }
+
+    #[test]
+    fn right_to_left_harmony() {
+        let h = vowel_harmony(|h| h.direction = Some(String::from("rtl")));
+        assert_eq!(harmonized(&["Ak","ev"],&h),"ekev");
+        assert_eq!(harmonized(&["Ak","at"],&h),"akat");
+    }
+
+    #[test]
+    fn morpheme_domains() {
+        let h = vowel_harmony(|h| h.domain = Some(String::from("morpheme")));
+        assert_eq!(harmonized(&["ev","lAr"],&h),"evlar");
+        assert_eq!(harmonized(&["ev","lerIn"],&h),"evlerin");
+    }
+
+    fn syllabic(template : &str, rules : &[&str]) -> SoundChanges {
+        let template = compile_template(template,&cats(),&Vec::new()).ok();
+        let sc = rules.iter().map(|r| RuleEntry::Rule(r.to_string())).collect();
+        match compile_rules(&sc,&cats(),&BTreeMap::new(),&Vec::new(),template) {
+            Ok(sc) => sc,
+            Err(e) => panic!("{:?}",e),
+        }
+    }
+
+    fn stress(position : StressEntry, heavy : Option<&str>) -> Option<StressConfig> {
+        Some(StressConfig { position, heavy : heavy.map(String::from) })
+    }
+
+    fn tone(spread : &str) -> Option<ToneConfig> {
+        let marks = [("H","\u{301}"),("L","\u{300}"),("HL","\u{302}")].iter().map(|(t,m)| (t.to_string(),m.to_string())).collect();
+        Some(ToneConfig { marks, spread : Some(String::from(spread)) })
+    }
+
+    // The word with stress and tone marked on it, as the phonetic rules see it
+    fn marked(template : &str, word : &str, stress : Option<StressConfig>, tone : Option<ToneConfig>, lexical : Option<&StressEntry>, melody : &[&str]) -> String {
+        let p = match compile_prosody(&stress,&tone,&cats(),&Vec::new()) {
+            Ok(p) => p,
+            Err(e) => panic!("{}",e),
         };
-        // TODO split multigraph parser into separate function
-        // running it separately for every single rule is comically inefficient...
-        s0 = sca(&s0,from_cats(rule0,&cat_vec,&mg_rep,verbose),verbose);
+        let melody = melody.iter().map(|t| t.to_string()).collect();
+        prosody(word,&p,&syllabic(template,&[]),lexical,&melody)
+    }
+
+    #[test]
+    fn rules_see_through_stress_and_tone() {
+        let intervocalic = syllabic("(C)V",&["t -> d / @V_@V"]);
+        let stressed = marked("(C)V","patata",stress(StressEntry::Named(String::from("penultimate")),None),None,None,&[]);
+        assert_eq!(stressed,"paˈtata");
+        assert_eq!(to_orthography(stressed,&intervocalic,None).0,"paˈdada");
+        let toned = marked("(C)V","pata",None,tone("right"),None,&["H","L"]);
+        assert_eq!(toned,"pa\u{301}ta\u{300}");
+        assert_eq!(to_orthography(toned,&intervocalic,None).0,"pa\u{301}da\u{300}");
+        // a rule that mentions the marks still sees them
+        let pretonic = syllabic("(C)V",&["t -> d / _ˈ"]);
+        assert_eq!(to_orthography(String::from("paˈtata"),&pretonic,None).0,"paˈtata");
+        let pretonic = syllabic("(C)V",&["t -> d / _@Vˈ"]);
+        assert_eq!(to_orthography(String::from("taˈtata"),&pretonic,None).0,"daˈtata");
+    }
+
+    fn named(position : &str) -> StressEntry {
+        StressEntry::Named(String::from(position))
+    }
+
+    #[test]
+    fn stress_positions() {
+        let at = |position : StressEntry, word : &str| marked("(C)V",word,stress(position,None),None,None,&[]);
+        assert_eq!(at(named("initial"),"patakana"),"ˈpatakana");
+        assert_eq!(at(named("final"),"patakana"),"patakaˈna");
+        assert_eq!(at(named("penultimate"),"patakana"),"pataˈkana");
+        assert_eq!(at(named("antepenultimate"),"patakana"),"paˈtakana");
+        assert_eq!(at(StressEntry::Syllable(2),"patakana"),"paˈtakana");
+        assert_eq!(at(StressEntry::Syllable(-1),"patakana"),"patakaˈna");
+        // words too short for the position are stressed as near to it as they can be
+        assert_eq!(at(named("antepenultimate"),"pata"),"ˈpata");
+        assert_eq!(at(StressEntry::Syllable(5),"pata"),"paˈta");
+        // each word of a phrase gets its own stress
+        assert_eq!(at(named("initial"),"pata kana"),"ˈpata ˈkana");
+    }
+
+    #[test]
+    fn light_syllables_pass_stress_on() {
+        let at = |position : &str, word : &str| marked("(C)V(C)",word,stress(named(position),Some("@C")),None,None,&[]);
+        assert_eq!(at("penultimate","patakanta"),"pataˈkanta");
+        assert_eq!(at("penultimate","patakana"),"paˈtakana");
+        assert_eq!(at("initial","pantaka"),"ˈpantaka");
+        assert_eq!(at("initial","patanka"),"paˈtanka");
+        // a monosyllable has nowhere else to put it
+        assert_eq!(at("penultimate","pa"),"ˈpa");
+    }
+
+    #[test]
+    fn lexical_stress_overrides_the_default() {
+        let at = |lexical : StressEntry, heavy : Option<&str>| marked("(C)V(C)","patakana",stress(named("penultimate"),heavy),None,Some(&lexical),&[]);
+        assert_eq!(at(named("final"),None),"patakaˈna");
+        assert_eq!(at(StressEntry::Syllable(1),None),"ˈpatakana");
+        // and isn't moved off a light syllable
+        assert_eq!(at(named("penultimate"),Some("@C")),"pataˈkana");
+    }
+
+    #[test]
+    fn tones_spread_from_the_edge() {
+        let at = |spread : &str, word : &str, melody : &[&str]| marked("(C)V",word,None,tone(spread),None,melody);
+        assert_eq!(at("right","patana",&["H"]),"pa\u{301}ta\u{301}na\u{301}");
+        assert_eq!(at("right","patana",&["H","L"]),"pa\u{301}ta\u{300}na\u{300}");
+        assert_eq!(at("left","patana",&["H","L"]),"pa\u{301}ta\u{301}na\u{300}");
+        assert_eq!(at("none","patana",&["H","L"]),"pa\u{301}ta\u{300}na");
+        // no melody, no marks
+        assert_eq!(at("right","patana",&[]),"patana");
+    }
+
+    #[test]
+    fn extra_tones_make_contours() {
+        let at = |spread : &str, word : &str, melody : &[&str]| marked("(C)V",word,None,tone(spread),None,melody);
+        assert_eq!(at("right","pa",&["H","L"]),"pa\u{302}");
+        assert_eq!(at("right","pata",&["L","H","L"]),"pa\u{300}ta\u{302}");
+        assert_eq!(at("left","pata",&["H","L","H"]),"pa\u{302}ta\u{301}");
+        // a contour without a mark of its own is written with the marks of its tones
+        assert_eq!(at("right","pa",&["L","H"]),"pa\u{300}\u{301}");
     }
-    unigraph_to_multigraph(&s0,&mg_rep)
 }